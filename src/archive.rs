@@ -0,0 +1,71 @@
+//! Архив законченных партий. Хранится ограниченное число партий; когда место кончается,
+//! вытесняется партия, к которой дольше всего не обращались.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    board::{Board, Color},
+    game::{GameId, Outcome},
+};
+
+/// Сколько партий хранит архив
+pub const ARCHIVE_CAPACITY: usize = 1000;
+
+/// Законченная партия в архиве
+#[derive(Debug, Clone)]
+pub struct ArchivedGame {
+    pub white: Option<String>,
+    pub black: Option<String>,
+    pub outcome: Outcome,
+    /// Позиция, с которой партия началась
+    pub start: Board,
+    /// Ходы партии в SAN
+    pub moves: Vec<String>,
+    /// Стороны, которые пошли в берсерк; победа такой стороны стоит лишнее очко
+    pub berserk: Vec<Color>,
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    capacity: usize,
+    games: HashMap<GameId, ArchivedGame>,
+    /// Партии от давно не запрошенных к недавним
+    recent: VecDeque<GameId>,
+}
+
+impl Archive {
+    pub fn new(capacity: usize) -> Archive {
+        Archive {
+            capacity,
+            games: HashMap::new(),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Сохранить партию, вытеснив самую давно запрошенную, если архив полон
+    pub fn insert(&mut self, id: GameId, game: ArchivedGame) {
+        if self.games.insert(id, game).is_some() {
+            self.touch(id);
+            return;
+        }
+        self.recent.push_back(id);
+        while self.recent.len() > self.capacity {
+            if let Some(oldest) = self.recent.pop_front() {
+                self.games.remove(&oldest);
+            }
+        }
+    }
+
+    /// Найти партию; запрошенная партия считается недавней
+    pub fn get(&mut self, id: GameId) -> Option<&ArchivedGame> {
+        if self.games.contains_key(&id) {
+            self.touch(id);
+        }
+        self.games.get(&id)
+    }
+
+    fn touch(&mut self, id: GameId) {
+        self.recent.retain(|&other| other != id);
+        self.recent.push_back(id);
+    }
+}
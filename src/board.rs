@@ -0,0 +1,1280 @@
+//! Авторитетное состояние шахматной доски, которое сервер хранит для каждой комнаты.
+//! Ходы приходят в координатной нотации (`e2e4`) и проверяются здесь, прежде чем
+//! `ChatServer` разошлёт их остальным участникам комнаты.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::rules;
+
+/// Цвет фигур, он же сторона, которая делает ход
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    White,
+    Black,
+}
+
+impl Color {
+    /// Противоположная сторона
+    pub fn opposite(self) -> Color {
+        match self {
+            Color::White => Color::Black,
+            Color::Black => Color::White,
+        }
+    }
+
+    /// Направление движения пешек по горизонталям
+    pub fn pawn_direction(self) -> i8 {
+        match self {
+            Color::White => 1,
+            Color::Black => -1,
+        }
+    }
+
+    /// Горизонталь, с которой пешки этого цвета начинают игру
+    fn pawn_rank(self) -> u8 {
+        match self {
+            Color::White => 1,
+            Color::Black => 6,
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Color::White => write!(f, "white"),
+            Color::Black => write!(f, "black"),
+        }
+    }
+}
+
+/// Тип фигуры без учёта цвета
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PieceKind {
+    Pawn,
+    Knight,
+    Bishop,
+    Rook,
+    Queen,
+    King,
+}
+
+/// Фигуры, в которые может превратиться пешка
+pub const PROMOTION_KINDS: [PieceKind; 4] = [
+    PieceKind::Queen,
+    PieceKind::Rook,
+    PieceKind::Bishop,
+    PieceKind::Knight,
+];
+
+impl PieceKind {
+    /// Фигура превращения по букве в конце хода: `q`, `r`, `b` или `n`
+    pub fn from_promotion_char(c: char) -> Option<PieceKind> {
+        match c {
+            'q' => Some(PieceKind::Queen),
+            'r' => Some(PieceKind::Rook),
+            'b' => Some(PieceKind::Bishop),
+            'n' => Some(PieceKind::Knight),
+            _ => None,
+        }
+    }
+}
+
+/// Фигура на доске
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Piece {
+    pub color: Color,
+    pub kind: PieceKind,
+}
+
+impl Piece {
+    pub fn new(color: Color, kind: PieceKind) -> Piece {
+        Piece { color, kind }
+    }
+
+    /// Фигура по букве из FEN
+    pub fn from_char(c: char) -> Option<Piece> {
+        let kind = match c.to_ascii_lowercase() {
+            'p' => PieceKind::Pawn,
+            'n' => PieceKind::Knight,
+            'b' => PieceKind::Bishop,
+            'r' => PieceKind::Rook,
+            'q' => PieceKind::Queen,
+            'k' => PieceKind::King,
+            _ => return None,
+        };
+        let color = if c.is_ascii_uppercase() {
+            Color::White
+        } else {
+            Color::Black
+        };
+        Some(Piece::new(color, kind))
+    }
+
+    /// Буква фигуры в FEN: заглавная у белых, строчная у чёрных
+    pub fn to_char(self) -> char {
+        let c = match self.kind {
+            PieceKind::Pawn => 'p',
+            PieceKind::Knight => 'n',
+            PieceKind::Bishop => 'b',
+            PieceKind::Rook => 'r',
+            PieceKind::Queen => 'q',
+            PieceKind::King => 'k',
+        };
+        match self.color {
+            Color::White => c.to_ascii_uppercase(),
+            Color::Black => c,
+        }
+    }
+}
+
+/// Поле доски: индекс от 0 (a1) до 63 (h8)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Square(u8);
+
+impl Square {
+    /// Поле по вертикали (0 = a) и горизонтали (0 = 1)
+    pub fn new(file: u8, rank: u8) -> Option<Square> {
+        if file < 8 && rank < 8 {
+            Some(Square(rank * 8 + file))
+        } else {
+            None
+        }
+    }
+
+    /// Разбирает поле в алгебраической записи, например `e4`
+    pub fn parse(s: &str) -> Option<Square> {
+        let mut chars = s.chars();
+        let file = chars.next()?;
+        let rank = chars.next()?;
+        if chars.next().is_some() || !('a'..='h').contains(&file) || !('1'..='8').contains(&rank) {
+            return None;
+        }
+        Square::new(file as u8 - b'a', rank as u8 - b'1')
+    }
+
+    pub fn file(self) -> u8 {
+        self.0 % 8
+    }
+
+    pub fn rank(self) -> u8 {
+        self.0 / 8
+    }
+
+    /// Все поля доски от a1 до h8
+    pub fn all() -> impl Iterator<Item = Square> {
+        (0..64).map(Square)
+    }
+
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Поле, смещённое на заданное число вертикалей и горизонталей, если оно не за краем доски
+    pub fn offset(self, files: i8, ranks: i8) -> Option<Square> {
+        let file = self.file() as i8 + files;
+        let rank = self.rank() as i8 + ranks;
+        if (0..8).contains(&file) && (0..8).contains(&rank) {
+            Square::new(file as u8, rank as u8)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Square {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", (b'a' + self.file()) as char, self.rank() + 1)
+    }
+}
+
+/// Ход в координатной нотации: откуда, куда и во что превращается пешка
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Move {
+    pub from: Square,
+    pub to: Square,
+    pub promotion: Option<PieceKind>,
+}
+
+impl Move {
+    pub fn new(from: Square, to: Square) -> Move {
+        Move {
+            from,
+            to,
+            promotion: None,
+        }
+    }
+
+    pub fn with_promotion(from: Square, to: Square, promotion: PieceKind) -> Move {
+        Move {
+            from,
+            to,
+            promotion: Some(promotion),
+        }
+    }
+
+    /// Разбирает ход вида `e2e4` или `e7e8q`, строго как в UCI: строчными буквами,
+    /// без разделителей
+    pub fn parse(s: &str) -> Option<Move> {
+        if !(4..=5).contains(&s.len()) || !s.is_ascii() {
+            return None;
+        }
+        let from = Square::parse(&s[0..2])?;
+        let to = Square::parse(&s[2..4])?;
+        match s[4..].chars().next() {
+            Some(c) => Some(Move::with_promotion(
+                from,
+                to,
+                PieceKind::from_promotion_char(c)?,
+            )),
+            None => Some(Move::new(from, to)),
+        }
+    }
+}
+
+impl Move {
+    /// Разбирает ход, записанный не совсем точно: `E2E4`, `e2-e4`, `e2 e4`, `e7e8=Q`.
+    /// Регистр не важен, дефисы, `=` и пробелы между полями отбрасываются.
+    pub fn parse_lenient(s: &str) -> Option<Move> {
+        let canonical: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-' && *c != '=')
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        Move::parse(&canonical)
+    }
+}
+
+impl fmt::Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.from, self.to)?;
+        if let Some(kind) = self.promotion {
+            write!(f, "{}", Piece::new(Color::Black, kind).to_char())?;
+        }
+        Ok(())
+    }
+}
+
+/// Почему ход не может быть сделан
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveError {
+    /// Фигура так не ходит, или это не фигура стороны, которая ходит
+    Illegal,
+    /// Пешка дошла до последней горизонтали, но фигура превращения не указана
+    MissingPromotion,
+    /// Фигура превращения указана у хода, который не является превращением
+    UnexpectedPromotion,
+    /// После хода собственный король остаётся или оказывается под шахом
+    KingInCheck,
+}
+
+impl MoveError {
+    /// Код ошибки для клиента
+    pub fn code(&self) -> &'static str {
+        match self {
+            MoveError::Illegal => "illegal_move",
+            MoveError::MissingPromotion => "missing_promotion",
+            MoveError::UnexpectedPromotion => "unexpected_promotion",
+            MoveError::KingInCheck => "king_in_check",
+        }
+    }
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::Illegal => write!(f, "illegal move"),
+            MoveError::MissingPromotion => write!(f, "promotion piece required"),
+            MoveError::UnexpectedPromotion => write!(f, "move is not a promotion"),
+            MoveError::KingInCheck => write!(f, "leaves king in check"),
+        }
+    }
+}
+
+pub const KNIGHT_OFFSETS: [(i8, i8); 8] = [
+    (1, 2),
+    (2, 1),
+    (2, -1),
+    (1, -2),
+    (-1, -2),
+    (-2, -1),
+    (-2, 1),
+    (-1, 2),
+];
+pub const BISHOP_DIRECTIONS: [(i8, i8); 4] = [(1, 1), (1, -1), (-1, -1), (-1, 1)];
+pub const ROOK_DIRECTIONS: [(i8, i8); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+pub const KING_OFFSETS: [(i8, i8); 8] = [
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+];
+
+const BACK_RANK: [PieceKind; 8] = [
+    PieceKind::Rook,
+    PieceKind::Knight,
+    PieceKind::Bishop,
+    PieceKind::Queen,
+    PieceKind::King,
+    PieceKind::Bishop,
+    PieceKind::Knight,
+    PieceKind::Rook,
+];
+
+/// Сколько начальных расстановок у шахмат Фишера
+pub const CHESS960_POSITIONS: u16 = 960;
+
+/// Расстановки двух коней на пяти полях, оставшихся после слонов и ферзя, в порядке
+/// нумерации Шарнагля
+const CHESS960_KNIGHTS: [(usize, usize); 10] = [
+    (0, 1),
+    (0, 2),
+    (0, 3),
+    (0, 4),
+    (1, 2),
+    (1, 3),
+    (1, 4),
+    (2, 3),
+    (2, 4),
+    (3, 4),
+];
+
+/// Почему строку не удалось разобрать как FEN
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FenError {
+    /// Должно быть шесть полей, разделённых пробелами
+    FieldCount(usize),
+    /// Расстановка должна состоять из восьми горизонталей
+    RankCount(usize),
+    /// В горизонтали не восемь полей
+    RankLength(u8),
+    /// Неизвестная буква фигуры
+    InvalidPiece(char),
+    /// У каждой стороны должен быть ровно один король
+    KingCount(Color, usize),
+    /// Слишком много фигур одной стороны
+    TooManyPieces(Color),
+    /// Пешка на первой или последней горизонтали
+    PawnOnBackRank,
+    /// Очередь хода должна быть `w` или `b`
+    InvalidSideToMove(String),
+    /// Поле рокировки должно быть `-` или сочетанием `KQkq`
+    InvalidCastling(String),
+    /// Право на рокировку без короля или ладьи на исходных полях
+    CastlingWithoutPieces(char),
+    /// Поле взятия на проходе должно быть `-` или полем третьей или шестой горизонтали
+    InvalidEnPassant(String),
+    /// Счётчик полуходов должен быть неотрицательным числом
+    InvalidHalfmoveClock(String),
+    /// Номер хода должен быть положительным числом
+    InvalidFullmoveNumber(String),
+    /// Король стороны, которая не ходит, под шахом
+    OpponentInCheck,
+}
+
+impl fmt::Display for FenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FenError::FieldCount(count) => write!(f, "expected 6 fields, found {}", count),
+            FenError::RankCount(count) => write!(f, "expected 8 ranks, found {}", count),
+            FenError::RankLength(rank) => write!(f, "rank {} does not have 8 squares", rank),
+            FenError::InvalidPiece(c) => write!(f, "invalid piece {:?}", c),
+            FenError::KingCount(color, count) => {
+                write!(f, "{} must have exactly one king, found {}", color, count)
+            }
+            FenError::TooManyPieces(color) => write!(f, "{} has too many pieces", color),
+            FenError::PawnOnBackRank => write!(f, "pawns cannot stand on the first or last rank"),
+            FenError::InvalidSideToMove(side) => write!(f, "invalid side to move {:?}", side),
+            FenError::InvalidCastling(castling) => {
+                write!(f, "invalid castling field {:?}", castling)
+            }
+            FenError::CastlingWithoutPieces(c) => {
+                write!(f, "castling right {:?} without king and rook in place", c)
+            }
+            FenError::InvalidEnPassant(square) => {
+                write!(f, "invalid en passant square {:?}", square)
+            }
+            FenError::InvalidHalfmoveClock(value) => {
+                write!(f, "invalid halfmove clock {:?}", value)
+            }
+            FenError::InvalidFullmoveNumber(value) => {
+                write!(f, "invalid fullmove number {:?}", value)
+            }
+            FenError::OpponentInCheck => write!(f, "the side not to move is in check"),
+        }
+    }
+}
+
+/// Права на рокировку, которые ещё не потеряны: вертикаль ладьи, с которой король может
+/// рокироваться. В обычных шахматах это вертикали `a` и `h`, в шахматах Фишера — любые.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct CastlingRights {
+    pub white_kingside: Option<u8>,
+    pub white_queenside: Option<u8>,
+    pub black_kingside: Option<u8>,
+    pub black_queenside: Option<u8>,
+}
+
+impl CastlingRights {
+    /// Вертикали ладей для рокировки в короткую и длинную сторону
+    fn rook_files(&self, color: Color) -> (Option<u8>, Option<u8>) {
+        match color {
+            Color::White => (self.white_kingside, self.white_queenside),
+            Color::Black => (self.black_kingside, self.black_queenside),
+        }
+    }
+
+    /// Снять оба права стороны: её король сходил
+    fn clear_color(&mut self, color: Color) {
+        match color {
+            Color::White => {
+                self.white_kingside = None;
+                self.white_queenside = None;
+            }
+            Color::Black => {
+                self.black_kingside = None;
+                self.black_queenside = None;
+            }
+        }
+    }
+
+    /// Снять право, связанное с полем: ладья ушла или её взяли
+    fn clear_square(&mut self, square: Square) {
+        let rights = match square.rank() {
+            0 => [&mut self.white_kingside, &mut self.white_queenside],
+            7 => [&mut self.black_kingside, &mut self.black_queenside],
+            _ => return,
+        };
+        for right in rights {
+            if *right == Some(square.file()) {
+                *right = None;
+            }
+        }
+    }
+}
+
+/// Права записываются как в X-FEN: `K` и `Q` для ладей в углах, буква вертикали ладьи
+/// для остальных, например `HBhb`.
+impl fmt::Display for CastlingRights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rights = [
+            (self.white_kingside, 7, 'K'),
+            (self.white_queenside, 0, 'Q'),
+            (self.black_kingside, 7, 'k'),
+            (self.black_queenside, 0, 'q'),
+        ];
+        let mut any = false;
+        for (right, corner, c) in rights {
+            if let Some(file) = right {
+                let letter = if file == corner {
+                    c
+                } else if c.is_ascii_uppercase() {
+                    (b'A' + file) as char
+                } else {
+                    (b'a' + file) as char
+                };
+                write!(f, "{}", letter)?;
+                any = true;
+            }
+        }
+        if !any {
+            write!(f, "-")?;
+        }
+        Ok(())
+    }
+}
+
+/// Позиция: расстановка фигур, очередь хода и всё остальное, что записывается в FEN
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Board {
+    squares: [Option<Piece>; 64],
+    side_to_move: Color,
+    castling: CastlingRights,
+    /// Поле, через которое пешка только что перепрыгнула двойным ходом
+    en_passant: Option<Square>,
+    /// Полуходы с последнего хода пешкой или взятия
+    halfmove_clock: u32,
+    /// Номер хода, растёт после каждого хода чёрных
+    fullmove_number: u32,
+}
+
+impl Default for Board {
+    fn default() -> Board {
+        Board::new()
+    }
+}
+
+impl Board {
+    /// Доска в начальной позиции, первыми ходят белые
+    pub fn new() -> Board {
+        Board::with_back_rank(BACK_RANK)
+    }
+
+    /// Начальная позиция шахмат Фишера с номером `number` по Шарнаглю, от 0 до 959.
+    /// Слоны стоят на полях разного цвета, король — между ладьями; позиция 518 — обычная
+    /// начальная.
+    pub fn chess960(number: u16) -> Board {
+        let mut number = usize::from(number % CHESS960_POSITIONS);
+        let mut back_rank = [None; 8];
+
+        back_rank[(number % 4) * 2 + 1] = Some(PieceKind::Bishop);
+        number /= 4;
+        back_rank[(number % 4) * 2] = Some(PieceKind::Bishop);
+        number /= 4;
+
+        let free = |back_rank: &[Option<PieceKind>; 8]| -> Vec<usize> {
+            (0..8).filter(|&file| back_rank[file].is_none()).collect()
+        };
+        back_rank[free(&back_rank)[number % 6]] = Some(PieceKind::Queen);
+        number /= 6;
+
+        let (first, second) = CHESS960_KNIGHTS[number];
+        let squares = free(&back_rank);
+        back_rank[squares[first]] = Some(PieceKind::Knight);
+        back_rank[squares[second]] = Some(PieceKind::Knight);
+
+        // на трёх оставшихся полях слева направо стоят ладья, король и ладья
+        for (file, kind) in
+            free(&back_rank)
+                .into_iter()
+                .zip([PieceKind::Rook, PieceKind::King, PieceKind::Rook])
+        {
+            back_rank[file] = Some(kind);
+        }
+
+        Board::with_back_rank(back_rank.map(|kind| kind.unwrap_or(PieceKind::Rook)))
+    }
+
+    /// Начальная позиция с заданной расстановкой фигур на первой и последней горизонталях.
+    /// Рокироваться можно с крайними ладьями.
+    fn with_back_rank(back_rank: [PieceKind; 8]) -> Board {
+        let mut board = Board::empty();
+        for (file, kind) in back_rank.iter().enumerate() {
+            let file = file as u8;
+            board.set(Square(file), Some(Piece::new(Color::White, *kind)));
+            board.set(
+                Square(8 + file),
+                Some(Piece::new(Color::White, PieceKind::Pawn)),
+            );
+            board.set(
+                Square(48 + file),
+                Some(Piece::new(Color::Black, PieceKind::Pawn)),
+            );
+            board.set(Square(56 + file), Some(Piece::new(Color::Black, *kind)));
+        }
+        let rooks: Vec<u8> = (0..8)
+            .filter(|&file| back_rank[file as usize] == PieceKind::Rook)
+            .collect();
+        if let (Some(&queenside), Some(&kingside)) = (rooks.first(), rooks.last()) {
+            board.castling = CastlingRights {
+                white_kingside: Some(kingside),
+                white_queenside: Some(queenside),
+                black_kingside: Some(kingside),
+                black_queenside: Some(queenside),
+            };
+        }
+        board
+    }
+
+    /// Пустая доска, ход белых
+    pub fn empty() -> Board {
+        Board {
+            squares: [None; 64],
+            side_to_move: Color::White,
+            castling: CastlingRights::default(),
+            en_passant: None,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        }
+    }
+
+    pub fn side_to_move(&self) -> Color {
+        self.side_to_move
+    }
+
+    pub fn halfmove_clock(&self) -> u32 {
+        self.halfmove_clock
+    }
+
+    pub fn fullmove_number(&self) -> u32 {
+        self.fullmove_number
+    }
+
+    pub fn piece_at(&self, square: Square) -> Option<Piece> {
+        self.squares[square.index()]
+    }
+
+    pub fn set(&mut self, square: Square, piece: Option<Piece>) {
+        self.squares[square.index()] = piece;
+    }
+
+    /// Зеркальная позиция для отладки генератора ходов: доска перевёрнута сверху вниз,
+    /// цвета фигур, права на рокировку и очередь хода поменяны местами. Допустимых ходов
+    /// в ней столько же, сколько в исходной.
+    pub fn mirror(&self) -> Board {
+        let flip = |square: Square| Square(square.0 ^ 56);
+        let mut mirrored = Board::empty();
+        for square in Square::all() {
+            let piece = self
+                .piece_at(square)
+                .map(|piece| Piece::new(piece.color.opposite(), piece.kind));
+            mirrored.set(flip(square), piece);
+        }
+        mirrored.side_to_move = self.side_to_move.opposite();
+        mirrored.castling = CastlingRights {
+            white_kingside: self.castling.black_kingside,
+            white_queenside: self.castling.black_queenside,
+            black_kingside: self.castling.white_kingside,
+            black_queenside: self.castling.white_queenside,
+        };
+        mirrored.en_passant = self.en_passant.map(flip);
+        mirrored.halfmove_clock = self.halfmove_clock;
+        mirrored.fullmove_number = self.fullmove_number;
+        mirrored
+    }
+
+    /// Все ходы стороны по правилам движения фигур, без учёта шаха своему королю
+    pub fn pseudo_legal_moves(&self, color: Color) -> Vec<Move> {
+        let mut moves = Vec::new();
+        for square in Square::all() {
+            self.pseudo_legal_moves_from(square, color, &mut moves);
+        }
+        moves
+    }
+
+    /// Проверяет, что ход соответствует правилам движения фигуры для текущей стороны,
+    /// и объясняет, почему он недопустим
+    pub fn check_move(&self, mv: &Move) -> Result<(), MoveError> {
+        let mut moves = Vec::new();
+        self.pseudo_legal_moves_from(mv.from, self.side_to_move, &mut moves);
+        if moves.contains(mv) {
+            return Ok(());
+        }
+
+        let same_squares = |other: &&Move| other.from == mv.from && other.to == mv.to;
+        match moves.iter().find(same_squares) {
+            Some(other) if other.promotion.is_some() => Err(MoveError::MissingPromotion),
+            Some(_) => Err(MoveError::UnexpectedPromotion),
+            None => Err(MoveError::Illegal),
+        }
+    }
+
+    /// Поле пешки, которую ход берёт на проходе: она стоит рядом, а не на поле,
+    /// куда пошла наша пешка
+    pub fn en_passant_capture(&self, mv: &Move) -> Option<Square> {
+        let pawn = matches!(self.piece_at(mv.from), Some(piece) if piece.kind == PieceKind::Pawn);
+        if pawn && self.piece_at(mv.to).is_none() && mv.from.file() != mv.to.file() {
+            Some(Square(mv.from.rank() * 8 + mv.to.file()))
+        } else {
+            None
+        }
+    }
+
+    /// Фигура, которую берёт ход, в том числе пешка, взятая на проходе. Рокировка, при которой
+    /// король встаёт на свою ладью, ничего не берёт.
+    pub fn captured_piece(&self, mv: &Move) -> Option<Piece> {
+        if self.castling(mv).is_some() {
+            return None;
+        }
+        match self.en_passant_capture(mv) {
+            Some(square) => self.piece_at(square),
+            None => self.piece_at(mv.to),
+        }
+    }
+
+    /// Откуда и куда идёт ладья, если ход — рокировка
+    pub fn castling_rook(&self, mv: &Move) -> Option<(Square, Square)> {
+        self.castling(mv)
+            .map(|(_, rook_from, rook_to)| (rook_from, rook_to))
+    }
+
+    /// Куда встают король и ладья, если ход — рокировка: `(король, откуда ладья, куда ладья)`.
+    /// Рокировка записывается ходом короля на свою ладью, а если король и ладья стоят
+    /// на обычных местах — ходом короля на две вертикали, например `e1g1`. После рокировки
+    /// король стоит на `g` или `c`, а ладья рядом с ним на `f` или `d`.
+    fn castling(&self, mv: &Move) -> Option<(Square, Square, Square)> {
+        let king = self
+            .piece_at(mv.from)
+            .filter(|piece| piece.kind == PieceKind::King)?;
+        let rank = match king.color {
+            Color::White => 0,
+            Color::Black => 7,
+        };
+        if mv.from.rank() != rank || mv.to.rank() != rank {
+            return None;
+        }
+
+        let rook = Some(Piece::new(king.color, PieceKind::Rook));
+        let rook_file = if self.piece_at(mv.to) == rook {
+            mv.to.file()
+        } else {
+            match (mv.from.file(), mv.to.file()) {
+                (4, 6) => 7,
+                (4, 2) => 0,
+                _ => return None,
+            }
+        };
+
+        let (kingside, queenside) = self.castling.rook_files(king.color);
+        let (right, king_to, rook_to) = if rook_file > mv.from.file() {
+            (kingside, 6, 5)
+        } else {
+            (queenside, 2, 3)
+        };
+        if right != Some(rook_file) {
+            return None;
+        }
+        Some((
+            Square(rank * 8 + king_to),
+            Square(rank * 8 + rook_file),
+            Square(rank * 8 + rook_to),
+        ))
+    }
+
+    /// Применяет ход без проверки и передаёт очередь другой стороне
+    pub fn apply(&mut self, mv: Move) {
+        let piece = self.piece_at(mv.from);
+        let castling = self.castling(&mv);
+        // при рокировке ходом на свою ладью на поле `to` никого не берут
+        let capture = castling.is_none() && self.piece_at(mv.to).is_some();
+        let pawn = matches!(piece, Some(piece) if piece.kind == PieceKind::Pawn);
+        let en_passant = self.en_passant_capture(&mv);
+
+        if let Some(captured) = en_passant {
+            self.set(captured, None);
+        }
+
+        if let Some((king_to, rook_from, rook_to)) = castling {
+            let rook = self.piece_at(rook_from);
+            self.set(mv.from, None);
+            self.set(rook_from, None);
+            self.set(king_to, piece);
+            self.set(rook_to, rook);
+        } else {
+            self.set(mv.from, None);
+            self.set(
+                mv.to,
+                match (piece, mv.promotion) {
+                    (Some(piece), Some(kind)) => Some(Piece::new(piece.color, kind)),
+                    _ => piece,
+                },
+            );
+        }
+
+        if let Some(piece) = piece.filter(|piece| piece.kind == PieceKind::King) {
+            self.castling.clear_color(piece.color);
+        }
+        self.castling.clear_square(mv.from);
+        self.castling.clear_square(mv.to);
+
+        self.en_passant = None;
+        if pawn && mv.from.rank().abs_diff(mv.to.rank()) == 2 {
+            self.en_passant = Square::new(mv.from.file(), (mv.from.rank() + mv.to.rank()) / 2);
+        }
+
+        if pawn || capture {
+            self.halfmove_clock = 0;
+        } else {
+            self.halfmove_clock += 1;
+        }
+        if self.side_to_move == Color::Black {
+            self.fullmove_number += 1;
+        }
+        self.side_to_move = self.side_to_move.opposite();
+    }
+
+    /// Разобрать позицию в нотации Форсайта–Эдвардса и проверить, что она возможна
+    pub fn from_fen(fen: &str) -> Result<Board, FenError> {
+        let fields: Vec<&str> = fen.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(FenError::FieldCount(fields.len()));
+        }
+
+        let mut board = Board::empty();
+
+        let ranks: Vec<&str> = fields[0].split('/').collect();
+        if ranks.len() != 8 {
+            return Err(FenError::RankCount(ranks.len()));
+        }
+        for (row, rank_str) in ranks.iter().enumerate() {
+            let rank = 7 - row as u8;
+            let mut file = 0u8;
+            for c in rank_str.chars() {
+                if let Some(skip) = c.to_digit(10).filter(|skip| (1..=8).contains(skip)) {
+                    file += skip as u8;
+                } else {
+                    let piece = Piece::from_char(c).ok_or(FenError::InvalidPiece(c))?;
+                    if file < 8 {
+                        board.set(Square(rank * 8 + file), Some(piece));
+                    }
+                    file += 1;
+                }
+                if file > 8 {
+                    return Err(FenError::RankLength(rank + 1));
+                }
+            }
+            if file != 8 {
+                return Err(FenError::RankLength(rank + 1));
+            }
+        }
+
+        for color in [Color::White, Color::Black] {
+            let pieces: Vec<(Square, Piece)> = Square::all()
+                .filter_map(|square| board.piece_at(square).map(|piece| (square, piece)))
+                .filter(|(_, piece)| piece.color == color)
+                .collect();
+            let kings = pieces
+                .iter()
+                .filter(|(_, piece)| piece.kind == PieceKind::King)
+                .count();
+            if kings != 1 {
+                return Err(FenError::KingCount(color, kings));
+            }
+            let pawns = pieces
+                .iter()
+                .filter(|(_, piece)| piece.kind == PieceKind::Pawn)
+                .count();
+            if pieces.len() > 16 || pawns > 8 {
+                return Err(FenError::TooManyPieces(color));
+            }
+            if pieces.iter().any(|(square, piece)| {
+                piece.kind == PieceKind::Pawn && (square.rank() == 0 || square.rank() == 7)
+            }) {
+                return Err(FenError::PawnOnBackRank);
+            }
+        }
+
+        board.side_to_move = match fields[1] {
+            "w" => Color::White,
+            "b" => Color::Black,
+            side => return Err(FenError::InvalidSideToMove(side.to_owned())),
+        };
+
+        // права на рокировку записываются как в X-FEN: `KQkq` означает крайних ладей,
+        // буква вертикали — ладью на этой вертикали
+        if fields[2] != "-" {
+            for c in fields[2].chars() {
+                let lower = c.to_ascii_lowercase();
+                if !matches!(lower, 'k' | 'q' | 'a'..='h') {
+                    return Err(FenError::InvalidCastling(fields[2].to_owned()));
+                }
+                let (color, rank) = if c.is_ascii_uppercase() {
+                    (Color::White, 0)
+                } else {
+                    (Color::Black, 7)
+                };
+                let holds = |file: u8, kind: PieceKind| {
+                    board.piece_at(Square(rank * 8 + file)) == Some(Piece::new(color, kind))
+                };
+
+                let king = (0..8).find(|&file| holds(file, PieceKind::King));
+                let rook = king.and_then(|king| match lower {
+                    'k' => (king + 1..8)
+                        .rev()
+                        .find(|&file| holds(file, PieceKind::Rook)),
+                    'q' => (0..king).find(|&file| holds(file, PieceKind::Rook)),
+                    _ => Some(lower as u8 - b'a').filter(|&file| holds(file, PieceKind::Rook)),
+                });
+                let (king, rook) = match (king, rook) {
+                    (Some(king), Some(rook)) => (king, rook),
+                    _ => return Err(FenError::CastlingWithoutPieces(c)),
+                };
+
+                let right = match (color, rook > king) {
+                    (Color::White, true) => &mut board.castling.white_kingside,
+                    (Color::White, false) => &mut board.castling.white_queenside,
+                    (Color::Black, true) => &mut board.castling.black_kingside,
+                    (Color::Black, false) => &mut board.castling.black_queenside,
+                };
+                if right.is_some() {
+                    return Err(FenError::InvalidCastling(fields[2].to_owned()));
+                }
+                *right = Some(rook);
+            }
+        }
+
+        if fields[3] != "-" {
+            // поле взятия на проходе лежит за пешкой, которая только что сделала двойной ход
+            let invalid = || FenError::InvalidEnPassant(fields[3].to_owned());
+            let square = Square::parse(fields[3]).ok_or_else(invalid)?;
+            let mover = board.side_to_move.opposite();
+            let expected_rank = match mover {
+                Color::White => 2,
+                Color::Black => 5,
+            };
+            let pawn = square.offset(0, mover.pawn_direction());
+            if square.rank() != expected_rank
+                || board.piece_at(square).is_some()
+                || pawn.and_then(|pawn| board.piece_at(pawn))
+                    != Some(Piece::new(mover, PieceKind::Pawn))
+            {
+                return Err(invalid());
+            }
+            board.en_passant = Some(square);
+        }
+
+        board.halfmove_clock = fields[4]
+            .parse()
+            .map_err(|_| FenError::InvalidHalfmoveClock(fields[4].to_owned()))?;
+        board.fullmove_number = fields[5]
+            .parse()
+            .ok()
+            .filter(|&number| number > 0)
+            .ok_or_else(|| FenError::InvalidFullmoveNumber(fields[5].to_owned()))?;
+
+        if rules::is_in_check(&board, board.side_to_move.opposite()) {
+            return Err(FenError::OpponentInCheck);
+        }
+
+        Ok(board)
+    }
+
+    /// Позиция в нотации Форсайта–Эдвардса
+    pub fn to_fen(&self) -> String {
+        format!(
+            "{} {} {}",
+            self.position_key(),
+            self.halfmove_clock,
+            self.fullmove_number
+        )
+    }
+
+    /// Первые четыре поля FEN: расстановка, очередь хода, рокировки и поле взятия на проходе.
+    /// Позиции с одинаковым ключом считаются одной позицией при подсчёте повторений.
+    pub fn position_key(&self) -> String {
+        let mut fen = String::new();
+
+        for rank in (0..8).rev() {
+            let mut empty = 0;
+            for file in 0..8 {
+                match self.squares[rank * 8 + file] {
+                    Some(piece) => {
+                        if empty > 0 {
+                            fen.push_str(&empty.to_string());
+                            empty = 0;
+                        }
+                        fen.push(piece.to_char());
+                    }
+                    None => empty += 1,
+                }
+            }
+            if empty > 0 {
+                fen.push_str(&empty.to_string());
+            }
+            if rank > 0 {
+                fen.push('/');
+            }
+        }
+
+        let side = match self.side_to_move {
+            Color::White => 'w',
+            Color::Black => 'b',
+        };
+        let en_passant = self
+            .en_passant
+            .map_or_else(|| "-".to_owned(), |square| square.to_string());
+
+        format!("{} {} {} {}", fen, side, self.castling, en_passant)
+    }
+
+    fn pseudo_legal_moves_from(&self, from: Square, color: Color, moves: &mut Vec<Move>) {
+        let piece = match self.piece_at(from) {
+            Some(piece) if piece.color == color => piece,
+            _ => return,
+        };
+
+        match piece.kind {
+            PieceKind::Pawn => self.pawn_moves(from, piece.color, moves),
+            PieceKind::Knight => self.step_moves(from, piece.color, &KNIGHT_OFFSETS, moves),
+            PieceKind::Bishop => self.slide_moves(from, piece.color, &BISHOP_DIRECTIONS, moves),
+            PieceKind::Rook => self.slide_moves(from, piece.color, &ROOK_DIRECTIONS, moves),
+            PieceKind::Queen => {
+                self.slide_moves(from, piece.color, &BISHOP_DIRECTIONS, moves);
+                self.slide_moves(from, piece.color, &ROOK_DIRECTIONS, moves);
+            }
+            PieceKind::King => {
+                self.step_moves(from, piece.color, &KING_OFFSETS, moves);
+                self.castling_moves(from, piece.color, moves);
+            }
+        }
+    }
+
+    /// Рокировки: король встаёт на `g` или `c`, ладья рядом с ним на `f` или `d`, как при
+    /// обычной рокировке, даже если они начинали не с `e` и углов. Король и ладья не должны
+    /// были ходить, поля, которые они проходят и занимают, должны быть пусты, кроме полей
+    /// самих короля и ладьи, а король не может быть под шахом, проходить через битое поле
+    /// или вставать на него. Как записывается ход, объясняет `castling`.
+    fn castling_moves(&self, from: Square, color: Color, moves: &mut Vec<Move>) {
+        let rank = match color {
+            Color::White => 0,
+            Color::Black => 7,
+        };
+        if from.rank() != rank {
+            return;
+        }
+
+        let opponent = color.opposite();
+        let rook = Some(Piece::new(color, PieceKind::Rook));
+        let king_file = from.file();
+        let span = |a: u8, b: u8| a.min(b)..=a.max(b);
+        let (kingside, queenside) = self.castling.rook_files(color);
+
+        for (right, king_to, rook_to) in [(kingside, 6, 5), (queenside, 2, 3)] {
+            let rook_file = match right {
+                Some(file) if self.piece_at(Square(rank * 8 + file)) == rook => file,
+                _ => continue,
+            };
+            let empty = span(king_file, king_to)
+                .chain(span(rook_file, rook_to))
+                .filter(|&file| file != king_file && file != rook_file)
+                .all(|file| self.piece_at(Square(rank * 8 + file)).is_none());
+            let safe = span(king_file, king_to)
+                .all(|file| !rules::is_square_attacked(self, Square(rank * 8 + file), opponent));
+            if !empty || !safe {
+                continue;
+            }
+
+            let to = if king_file == 4 && (rook_file == 0 || rook_file == 7) {
+                king_to
+            } else {
+                rook_file
+            };
+            moves.push(Move::new(from, Square(rank * 8 + to)));
+        }
+    }
+
+    fn pawn_moves(&self, from: Square, color: Color, moves: &mut Vec<Move>) {
+        let direction = color.pawn_direction();
+
+        // на последней горизонтали пешка обязана превратиться в фигуру
+        let push = |to: Square, moves: &mut Vec<Move>| {
+            if to.rank() == 0 || to.rank() == 7 {
+                for kind in PROMOTION_KINDS {
+                    moves.push(Move::with_promotion(from, to, kind));
+                }
+            } else {
+                moves.push(Move::new(from, to));
+            }
+        };
+
+        if let Some(one) = from.offset(0, direction) {
+            if self.piece_at(one).is_none() {
+                push(one, moves);
+
+                if from.rank() == color.pawn_rank() {
+                    if let Some(two) = one.offset(0, direction) {
+                        if self.piece_at(two).is_none() {
+                            moves.push(Move::new(from, two));
+                        }
+                    }
+                }
+            }
+        }
+
+        for files in [-1, 1] {
+            if let Some(to) = from.offset(files, direction) {
+                let capture = matches!(self.piece_at(to), Some(target) if target.color != color);
+                if capture || self.en_passant == Some(to) {
+                    push(to, moves);
+                }
+            }
+        }
+    }
+
+    fn step_moves(&self, from: Square, color: Color, offsets: &[(i8, i8)], moves: &mut Vec<Move>) {
+        for &(files, ranks) in offsets {
+            if let Some(to) = from.offset(files, ranks) {
+                match self.piece_at(to) {
+                    Some(target) if target.color == color => {}
+                    _ => moves.push(Move::new(from, to)),
+                }
+            }
+        }
+    }
+
+    fn slide_moves(
+        &self,
+        from: Square,
+        color: Color,
+        directions: &[(i8, i8)],
+        moves: &mut Vec<Move>,
+    ) {
+        for &(files, ranks) in directions {
+            let mut current = from;
+            while let Some(to) = current.offset(files, ranks) {
+                match self.piece_at(to) {
+                    None => moves.push(Move::new(from, to)),
+                    Some(target) => {
+                        if target.color != color {
+                            moves.push(Move::new(from, to));
+                        }
+                        break;
+                    }
+                }
+                current = to;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(fen: &str) -> Board {
+        Board::from_fen(fen).unwrap()
+    }
+
+    fn square(name: &str) -> Square {
+        Square::parse(name).unwrap()
+    }
+
+    fn castle(board: &mut Board, mv: &str) {
+        let mv = Move::parse(mv).unwrap();
+        assert_eq!(board.check_move(&mv), Ok(()));
+        board.apply(mv);
+    }
+
+    #[test]
+    fn kingside_castling() {
+        let mut position = board("r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1");
+        castle(&mut position, "e1g1");
+        assert_eq!(
+            position.piece_at(square("g1")),
+            Some(Piece::new(Color::White, PieceKind::King))
+        );
+        assert_eq!(
+            position.piece_at(square("f1")),
+            Some(Piece::new(Color::White, PieceKind::Rook))
+        );
+        assert_eq!(position.piece_at(square("h1")), None);
+        assert_eq!(position.piece_at(square("e1")), None);
+        assert_eq!(position.to_fen(), "r3k2r/8/8/8/8/8/8/R4RK1 b kq - 1 1");
+    }
+
+    #[test]
+    fn queenside_castling() {
+        let mut position = board("r3k2r/8/8/8/8/8/8/R3K2R b KQkq - 0 1");
+        castle(&mut position, "e8c8");
+        assert_eq!(
+            position.piece_at(square("c8")),
+            Some(Piece::new(Color::Black, PieceKind::King))
+        );
+        assert_eq!(
+            position.piece_at(square("d8")),
+            Some(Piece::new(Color::Black, PieceKind::Rook))
+        );
+        assert_eq!(position.piece_at(square("a8")), None);
+        assert_eq!(position.to_fen(), "2kr3r/8/8/8/8/8/8/R3K2R w KQ - 1 2");
+    }
+
+    #[test]
+    fn castling_through_check_is_refused() {
+        // чёрная ладья бьёт f1, через которое король прошёл бы в короткую сторону
+        let position = board("4kr2/8/8/8/8/8/8/R3K2R w KQ - 0 1");
+        assert_eq!(
+            position.check_move(&Move::parse("e1g1").unwrap()),
+            Err(MoveError::Illegal)
+        );
+        assert_eq!(position.check_move(&Move::parse("e1c1").unwrap()), Ok(()));
+    }
+
+    #[test]
+    fn castling_out_of_check_is_refused() {
+        let position = board("4k3/8/8/8/8/8/4r3/R3K2R w KQ - 0 1");
+        assert_eq!(
+            position.check_move(&Move::parse("e1g1").unwrap()),
+            Err(MoveError::Illegal)
+        );
+        assert_eq!(
+            position.check_move(&Move::parse("e1c1").unwrap()),
+            Err(MoveError::Illegal)
+        );
+    }
+
+    fn play(board: &mut Board, moves: &[&str]) {
+        for mv in moves {
+            let mv = Move::parse(mv).unwrap();
+            assert_eq!(board.check_move(&mv), Ok(()), "{}", mv);
+            board.apply(mv);
+        }
+    }
+
+    #[test]
+    fn en_passant_capture() {
+        let mut position = Board::new();
+        play(&mut position, &["e2e4", "a7a6", "e4e5", "d7d5"]);
+        let capture = Move::parse("e5d6").unwrap();
+        assert_eq!(position.check_move(&capture), Ok(()));
+        assert_eq!(position.en_passant_capture(&capture), Some(square("d5")));
+        assert_eq!(
+            position.captured_piece(&capture),
+            Some(Piece::new(Color::Black, PieceKind::Pawn))
+        );
+
+        position.apply(capture);
+        assert_eq!(position.piece_at(square("d5")), None);
+        assert_eq!(
+            position.piece_at(square("d6")),
+            Some(Piece::new(Color::White, PieceKind::Pawn))
+        );
+    }
+
+    #[test]
+    fn en_passant_expires_after_one_move() {
+        let mut position = Board::new();
+        play(
+            &mut position,
+            &["e2e4", "a7a6", "e4e5", "d7d5", "a2a3", "h7h6"],
+        );
+        assert_eq!(
+            position.check_move(&Move::parse("e5d6").unwrap()),
+            Err(MoveError::Illegal)
+        );
+    }
+
+    /// Позиции с рокировками, взятием на проходе, превращениями и связками
+    const POSITIONS: [&str; 6] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+        "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+        "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+        "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+        "rnbqkbnr/ppp1p1pp/8/3pPp2/8/8/PPPP1PPP/RNBQKBNR w KQkq f6 0 3",
+    ];
+
+    #[test]
+    fn mirror_keeps_legal_move_count() {
+        for fen in POSITIONS {
+            let position = board(fen);
+            let mirrored = position.mirror();
+            assert_eq!(
+                rules::legal_moves(&position, position.side_to_move()).len(),
+                rules::legal_moves(&mirrored, mirrored.side_to_move()).len(),
+                "{}",
+                fen
+            );
+        }
+    }
+
+    #[test]
+    fn mirror_twice_is_identity() {
+        for fen in POSITIONS {
+            assert_eq!(board(fen).mirror().mirror().to_fen(), fen);
+        }
+    }
+
+    #[test]
+    fn mirror_swaps_colors_and_side() {
+        let mirrored = board("4k3/8/8/8/8/8/4P3/4K3 w - - 0 1").mirror();
+        assert_eq!(mirrored.to_fen(), "4k3/4p3/8/8/8/8/8/4K3 b - - 0 1");
+    }
+}
@@ -0,0 +1,20 @@
+//! Простейший соперник для игры в одиночку: ходит случайным допустимым ходом.
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{
+    board::{Board, Color, Move},
+    rules,
+};
+
+/// Идентификатор, которым бот занимает место за доской. Сессиям он не выдаётся.
+pub const BOT_ID: usize = usize::MAX;
+
+/// Имя бота в списке игроков и PGN
+pub const BOT_NAME: &str = "Random bot";
+
+/// Случайный допустимый ход стороны; `None`, если ходить нечем. С одним и тем же
+/// состоянием `rng` в одной позиции выбирается один и тот же ход.
+pub fn random_move(board: &Board, side: Color, rng: &mut impl Rng) -> Option<Move> {
+    rules::legal_moves(board, side).choose(rng).copied()
+}
@@ -0,0 +1,232 @@
+//! Шахматные часы: оставшееся время каждой стороны и добавка за ход. Для партий по переписке
+//! часы отсчитывают не время на всю партию, а срок на каждый ход.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::board::Color;
+
+/// Наибольший срок на ход в партии по переписке, в днях
+const MAX_DAYS_PER_MOVE: u64 = 14;
+
+/// Контроль времени партии. У сторон может быть разное время, например, когда сильный
+/// игрок даёт фору. В JSON записывается в секундах: `{"initial":300,"increment":2}`,
+/// а время одной из сторон можно задать отдельно: `{"initial":300,"black_initial":180}`.
+/// Партия по переписке задаётся сроком на ход в днях: `{"days_per_move":3}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "TimeControl")]
+pub struct ClockConfig {
+    /// Время белых на всю партию
+    pub white_initial: Duration,
+    /// Время чёрных на всю партию
+    pub black_initial: Duration,
+    /// Добавка, которую белые получают после каждого своего хода
+    pub white_increment: Duration,
+    /// Добавка, которую чёрные получают после каждого своего хода
+    pub black_increment: Duration,
+    /// Партия по переписке: время не копится, после каждого хода сторона снова получает
+    /// всё своё время на следующий ход
+    pub per_move: bool,
+}
+
+impl ClockConfig {
+    /// Время стороны на всю партию
+    pub fn initial(&self, color: Color) -> Duration {
+        match color {
+            Color::White => self.white_initial,
+            Color::Black => self.black_initial,
+        }
+    }
+
+    /// Добавка стороны за ход
+    pub fn increment(&self, color: Color) -> Duration {
+        match color {
+            Color::White => self.white_increment,
+            Color::Black => self.black_increment,
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> ClockConfig {
+        let initial = Duration::from_secs(10 * 60);
+        ClockConfig {
+            white_initial: initial,
+            black_initial: initial,
+            white_increment: Duration::ZERO,
+            black_increment: Duration::ZERO,
+            per_move: false,
+        }
+    }
+}
+
+/// Контроль времени в том виде, в каком он приходит в JSON: общие значения и необязательные
+/// значения для каждой стороны, которые важнее общих
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeControl {
+    initial: Option<u64>,
+    #[serde(default)]
+    increment: u64,
+    white_initial: Option<u64>,
+    black_initial: Option<u64>,
+    white_increment: Option<u64>,
+    black_increment: Option<u64>,
+    days_per_move: Option<u64>,
+}
+
+impl TryFrom<TimeControl> for ClockConfig {
+    type Error = &'static str;
+
+    fn try_from(control: TimeControl) -> Result<ClockConfig, Self::Error> {
+        if let Some(days) = control.days_per_move {
+            let other = [
+                control.initial,
+                control.white_initial,
+                control.black_initial,
+                control.white_increment,
+                control.black_increment,
+            ];
+            if control.increment != 0 || other.iter().any(Option::is_some) {
+                return Err("days_per_move cannot be combined with other time settings");
+            }
+            if !(1..=MAX_DAYS_PER_MOVE).contains(&days) {
+                return Err("days_per_move must be from 1 to 14");
+            }
+            let per_move = Duration::from_secs(days * 24 * 60 * 60);
+            return Ok(ClockConfig {
+                white_initial: per_move,
+                black_initial: per_move,
+                white_increment: Duration::ZERO,
+                black_increment: Duration::ZERO,
+                per_move: true,
+            });
+        }
+
+        let initial = |own: Option<u64>| match own.or(control.initial) {
+            Some(0) => Err("initial time must be positive"),
+            Some(seconds) => Ok(Duration::from_secs(seconds)),
+            None => Err("initial time is required"),
+        };
+        let increment = |own: Option<u64>| Duration::from_secs(own.unwrap_or(control.increment));
+        Ok(ClockConfig {
+            white_initial: initial(control.white_initial)?,
+            black_initial: initial(control.black_initial)?,
+            white_increment: increment(control.white_increment),
+            black_increment: increment(control.black_increment),
+            per_move: false,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    config: ClockConfig,
+    white: Duration,
+    black: Duration,
+    /// Чьи часы идут и с какого момента
+    running: Option<(Color, Instant)>,
+}
+
+impl Clock {
+    pub fn new(config: ClockConfig) -> Clock {
+        Clock {
+            config,
+            white: config.initial(Color::White),
+            black: config.initial(Color::Black),
+            running: None,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
+
+    /// Идут ли часы по сроку на ход, как в партии по переписке
+    pub fn is_per_move(&self) -> bool {
+        self.config.per_move
+    }
+
+    /// Запустить часы стороны
+    pub fn start(&mut self, color: Color, now: Instant) {
+        self.running = Some((color, now));
+    }
+
+    /// Остановить часы, списав уже потраченное время
+    pub fn stop(&mut self, now: Instant) {
+        if let Some((color, _)) = self.running {
+            *self.stored_mut(color) = self.remaining(color, now);
+        }
+        self.running = None;
+    }
+
+    /// Оставшееся время стороны на момент `now`
+    pub fn remaining(&self, color: Color, now: Instant) -> Duration {
+        let stored = match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        };
+        match self.running {
+            Some((running, since)) if running == color => {
+                stored.saturating_sub(now.saturating_duration_since(since))
+            }
+            _ => stored,
+        }
+    }
+
+    /// Сторона, у которой закончилось время
+    pub fn flagged(&self, now: Instant) -> Option<Color> {
+        match self.running {
+            Some((color, _)) if self.remaining(color, now).is_zero() => Some(color),
+            _ => None,
+        }
+    }
+
+    /// Переключить часы после хода: сходившей стороне начисляется добавка, а в партии
+    /// по переписке — снова весь срок на ход, и начинают идти часы соперника
+    pub fn switch(&mut self, now: Instant) {
+        if let Some((color, _)) = self.running {
+            let increment = self.config.increment(color);
+            self.stop(now);
+            if self.config.per_move {
+                *self.stored_mut(color) = self.config.initial(color);
+            } else {
+                *self.stored_mut(color) += increment;
+            }
+            self.start(color.opposite(), now);
+        }
+    }
+
+    /// Вернуть стороне всё её время на партию. Часы, которые шли, продолжают идти.
+    pub fn reset(&mut self, color: Color, now: Instant) {
+        let running = self.running.map(|(running, _)| running);
+        self.stop(now);
+        *self.stored_mut(color) = self.config.initial(color);
+        if let Some(running) = running {
+            self.start(running, now);
+        }
+    }
+
+    /// Берсерк: у стороны остаётся половина её времени, и добавки за ход она больше
+    /// не получает. Часы, которые шли, продолжают идти.
+    pub fn berserk(&mut self, color: Color, now: Instant) {
+        let running = self.running.map(|(running, _)| running);
+        self.stop(now);
+        *self.stored_mut(color) /= 2;
+        match color {
+            Color::White => self.config.white_increment = Duration::ZERO,
+            Color::Black => self.config.black_increment = Duration::ZERO,
+        }
+        if let Some(running) = running {
+            self.start(running, now);
+        }
+    }
+
+    fn stored_mut(&mut self, color: Color) -> &mut Duration {
+        match color {
+            Color::White => &mut self.white,
+            Color::Black => &mut self.black,
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Разбор текстовых команд, которые клиент присылает по вебсокету.
+//! Команды начинаются с `/`, всё остальное считается сообщением чата.
+
+use std::fmt;
+
+use crate::{
+    board::{Color, Move, Square},
+    clock::ClockConfig,
+    game::{GameConfig, GameId},
+    san,
+};
+
+/// Наибольшая длина имени сессии или названия комнаты в символах
+const MAX_NAME_LENGTH: usize = 32;
+
+/// Во сколько раз быстрее обычного можно показывать партию из архива
+pub const MAX_REPLAY_SPEED: u32 = 10;
+
+/// Наибольшая глубина `/perft`: подсчёт идёт на сервере, и глубже он заметно его задержит
+pub const MAX_PERFT_DEPTH: u32 = 3;
+
+/// Доступны ли отладочные команды. Их нет в релизной сборке: они нужны только при
+/// разработке правил.
+pub const DEBUG_COMMANDS: bool = cfg!(debug_assertions);
+
+/// Команда клиента
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientCommand {
+    /// `/join <room> [password]`
+    Join {
+        room: String,
+        password: Option<String>,
+    },
+    /// `/create <room> <password>`: создать комнату, закрытую паролем
+    Create { room: String, password: String },
+    /// `/create_game <room> <config>`: создать комнату с партией по настройкам в JSON
+    CreateGame { room: String, config: GameConfig },
+    /// `/spectate <room> [password]`: войти в комнату только зрителем
+    Spectate {
+        room: String,
+        password: Option<String>,
+    },
+    /// `/join_fen <room> <fen>`: создать комнату с партией из заданной позиции
+    JoinFen { room: String, fen: String },
+    /// `/join960 <room>`: создать комнату с партией из случайной расстановки шахмат Фишера
+    Join960(String),
+    /// `/solo <room>`: создать комнату с партией, в которой сессия играет за обе стороны
+    Solo(String),
+    /// `/load_pgn <room> <pgn>`: создать комнату с партией, в которой уже сделаны ходы из PGN;
+    /// PGN может занимать несколько строк
+    LoadPgn { room: String, pgn: String },
+    /// `/name <name>`
+    Name(String),
+    /// `/engine_mode`: включить или выключить режим движка. В нём ход можно прислать
+    /// просто строкой UCI, например `e7e8q`, и ходы соперника приходят такими же строками.
+    EngineMode,
+    /// `/check_move <move>`: допустим ли ход в текущей позиции; ход не делается
+    CheckMove(String),
+    /// `/hint`: подсказать ход стороне, которая ходит
+    Hint,
+    /// `/chess_step [room] [ply] <move>` (или `/chess-step`): ход в координатной нотации
+    /// или в SAN в указанной комнате, по умолчанию в текущей. Номер полухода, если он указан,
+    /// должен совпасть с номером, под которым ход войдёт в партию; комнату, названную одними
+    /// цифрами, так указать нельзя.
+    ChessStep {
+        room: Option<String>,
+        ply: Option<usize>,
+        step: String,
+    },
+    /// `/premove <move>`: ход, который будет сделан, как только придёт очередь
+    Premove(String),
+    /// `/leave [room]`: покинуть комнату, по умолчанию текущую
+    Leave(Option<String>),
+    /// `/list`
+    ListRooms,
+    /// `/games`: партии всех игровых комнат с игроками, состоянием и числом ходов
+    ListGames,
+    /// `/whoami`
+    WhoAmI,
+    /// `/orientation <white|black>`: какой стороной к себе показывать доску
+    Orientation(Color),
+    /// `/turn`
+    Turn,
+    /// `/status`: номер хода, счётчик полуходов, очередь и результат партии
+    Status,
+    /// `/stats`: сводка по всему серверу
+    Stats,
+    /// `/players [room]`: игроки и зрители комнаты, по умолчанию текущей
+    Players(Option<String>),
+    /// `/moves <square>`: куда может пойти фигура с поля
+    Moves(Square),
+    /// `/history`
+    History,
+    /// `/pgn`
+    Pgn,
+    /// `/rating <name>`
+    Rating(String),
+    /// `/archive <id>`: ходы законченной партии из архива
+    Archive(GameId),
+    /// `/replay <id> [speed]`: показать партию из архива ход за ходом; `speed` от 1
+    /// до `MAX_REPLAY_SPEED` ускоряет показ
+    Replay { id: GameId, speed: u32 },
+    /// `/replay_stop`: прекратить показ партии из архива
+    ReplayStop,
+    /// `/resign` (или `/give_up`)
+    Resign,
+    /// `/abort`: прервать партию, пока в ней не было ходов
+    Abort,
+    /// `/claim_draw`
+    ClaimDraw,
+    /// `/offer_draw`
+    OfferDraw,
+    /// `/accept_draw`
+    AcceptDraw,
+    /// `/decline_draw`
+    DeclineDraw,
+    /// `/propose_time <config>`: до первого хода предложить сопернику другой контроль
+    /// времени в JSON, например `{"initial":180,"increment":2}`, или `null` для партии без часов
+    ProposeTime(Option<ClockConfig>),
+    /// `/accept_time`: согласиться на контроль времени, предложенный соперником
+    AcceptTime,
+    /// `/draw_status`: есть ли в партии предложение ничьей
+    DrawStatus,
+    /// `/offer_takeback`
+    OfferTakeback,
+    /// `/accept_takeback`
+    AcceptTakeback,
+    /// `/berserk`: до своего первого хода отдать половину времени за очко сверху при победе
+    Berserk,
+    /// `/mirror`: отладочная команда игрока, отражает позицию сверху вниз и меняет цвета;
+    /// только при `DEBUG_COMMANDS`
+    Mirror,
+    /// `/perft <depth>`: отладочная команда, сколько позиций получается из текущей за `depth`
+    /// полуходов, от 1 до `MAX_PERFT_DEPTH`; только при `DEBUG_COMMANDS`, потому что считает
+    /// сервер комнат и на это время он занят
+    Perft(u32),
+    /// `/restart` или `/restart_game`: сыграть ещё одну партию с тем же соперником,
+    /// поменявшись цветами
+    Rematch,
+    /// `/quickmatch`: встать в очередь на быструю партию
+    QuickMatch,
+    /// `/play_bot`: сыграть против бота
+    PlayBot,
+    /// `/promote`: зритель занимает освободившееся место за доской
+    Promote,
+    /// `/flag`: потребовать победы, если время соперника вышло
+    Flag,
+    /// `/want_seat`: занять место игрока, если тот отключится и не вернётся
+    WantSeat,
+    /// `/pass_seat <spectator_id>`: уступить своё место и время зрителю той же комнаты
+    PassSeat(usize),
+    /// `/say`, `/say_players` или `/say_spectators`: сообщение чата для части комнаты
+    Say { scope: ChatScope, text: String },
+    /// `/mute_spectators` и `/unmute_spectators`: не получать сообщения зрителей или снова
+    /// получать их
+    MuteSpectators(bool),
+    /// Обычное сообщение чата
+    Chat(String),
+}
+
+/// Кому в комнате предназначено сообщение чата
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatScope {
+    /// Всем в комнате
+    All,
+    /// Только игрокам партии
+    Players,
+    /// Только зрителям
+    Spectators,
+}
+
+/// Ошибка разбора команды
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// У команды нет обязательного аргумента
+    MissingArgument(&'static str),
+    /// Аргумент команды не удалось разобрать
+    InvalidArgument(&'static str),
+    /// Имя или название комнаты слишком длинное или содержит управляющие символы
+    InvalidName(&'static str),
+    /// Ход не удалось привести к виду `e2e4`
+    MalformedMove(String),
+    /// Настройки партии не разбираются; указано, что в них не так
+    InvalidConfig(String),
+    /// Неизвестная команда
+    UnknownCommand(String),
+}
+
+impl ParseError {
+    /// Код ошибки для клиента
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::MissingArgument(_) => "missing_argument",
+            ParseError::InvalidArgument(_) => "invalid_argument",
+            ParseError::InvalidName(_) => "invalid_name",
+            ParseError::MalformedMove(_) => "malformed_move",
+            ParseError::InvalidConfig(_) => "invalid_config",
+            ParseError::UnknownCommand(_) => "unknown_command",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingArgument(argument) => write!(f, "!!! {} is required", argument),
+            ParseError::InvalidArgument(what) => write!(f, "!!! invalid {}", what),
+            ParseError::InvalidName(what) => write!(f, "invalid {}", what),
+            ParseError::MalformedMove(step) => write!(f, "malformed move: {}", step),
+            ParseError::InvalidConfig(err) => write!(f, "invalid game config: {}", err),
+            ParseError::UnknownCommand(command) => write!(f, "!!! unknown command: {:?}", command),
+        }
+    }
+}
+
+impl ClientCommand {
+    /// Разобрать текст сообщения от клиента
+    pub fn parse(text: &str) -> Result<ClientCommand, ParseError> {
+        let text = text.trim();
+        if !text.starts_with('/') {
+            return Ok(ClientCommand::Chat(text.to_owned()));
+        }
+
+        let (name, argument) = match text.split_once(' ') {
+            Some((name, argument)) => (name, Some(argument.trim()).filter(|a| !a.is_empty())),
+            None => (text, None),
+        };
+        let required = |what: &'static str| {
+            argument
+                .map(str::to_owned)
+                .ok_or(ParseError::MissingArgument(what))
+        };
+        // название комнаты и пароль, если он указан после названия
+        let room_and_password = || {
+            let argument = required("room name")?;
+            let (room, password) = match argument.split_once(' ') {
+                Some((room, password)) => (room.to_owned(), Some(password.trim().to_owned())),
+                None => (argument, None),
+            };
+            Ok((validate_name(room, "room name")?, password))
+        };
+
+        match name {
+            "/join" => {
+                room_and_password().map(|(room, password)| ClientCommand::Join { room, password })
+            }
+            "/create" => match room_and_password()? {
+                (room, Some(password)) => Ok(ClientCommand::Create { room, password }),
+                (_, None) => Err(ParseError::MissingArgument("password")),
+            },
+            "/create_game" => {
+                let argument = required("room name")?;
+                match argument.split_once(' ') {
+                    Some((room, config)) => Ok(ClientCommand::CreateGame {
+                        room: validate_name(room.to_owned(), "room name")?,
+                        config: serde_json::from_str(config)
+                            .map_err(|err| ParseError::InvalidConfig(err.to_string()))?,
+                    }),
+                    None => Err(ParseError::MissingArgument("game config")),
+                }
+            }
+            "/spectate" => room_and_password()
+                .map(|(room, password)| ClientCommand::Spectate { room, password }),
+            "/join_fen" => {
+                let argument = required("room name")?;
+                match argument.split_once(' ') {
+                    Some((room, fen)) => Ok(ClientCommand::JoinFen {
+                        room: validate_name(room.to_owned(), "room name")?,
+                        fen: fen.trim().to_owned(),
+                    }),
+                    None => Err(ParseError::MissingArgument("fen")),
+                }
+            }
+            "/solo" => required("room name")
+                .and_then(|room| validate_name(room, "room name"))
+                .map(ClientCommand::Solo),
+            "/join960" => required("room name")
+                .and_then(|room| validate_name(room, "room name"))
+                .map(ClientCommand::Join960),
+            "/load_pgn" => {
+                let argument = required("room name")?;
+                match argument.split_once(char::is_whitespace) {
+                    Some((room, pgn)) => Ok(ClientCommand::LoadPgn {
+                        room: validate_name(room.to_owned(), "room name")?,
+                        pgn: pgn.trim().to_owned(),
+                    }),
+                    None => Err(ParseError::MissingArgument("pgn")),
+                }
+            }
+            "/engine_mode" => Ok(ClientCommand::EngineMode),
+            "/name" => required("name")
+                .and_then(|name| validate_name(name, "name"))
+                .map(ClientCommand::Name),
+            "/chess_step" | "/chess-step" => {
+                let argument = required("move")?;
+                // ход может быть записан с пробелом, `e2 e4`, поэтому комнатой первое слово
+                // считается, только если без него остаётся ход
+                let (mut room, mut step) = match argument.split_once(' ') {
+                    Some(_) if Move::parse_lenient(&argument).is_some() => (None, argument),
+                    Some((room, step)) => (Some(room.to_owned()), step.trim().to_owned()),
+                    None => (None, argument),
+                };
+                // номер полухода стоит прямо перед ходом
+                let mut ply = None;
+                let numbered = step
+                    .split_once(' ')
+                    .filter(|_| Move::parse_lenient(&step).is_none());
+                if let Some((number, rest)) = numbered {
+                    ply = Some(parse_ply(number)?);
+                    step = rest.trim().to_owned();
+                } else if let Some(number) = room.as_deref().and_then(|room| parse_ply(room).ok()) {
+                    ply = Some(number);
+                    room = None;
+                }
+                Ok(ClientCommand::ChessStep {
+                    room,
+                    ply,
+                    step: parse_step(&step)?,
+                })
+            }
+            "/check_move" => parse_step(&required("move")?).map(ClientCommand::CheckMove),
+            "/hint" => Ok(ClientCommand::Hint),
+            "/premove" => normalize_step(&required("move")?).map(ClientCommand::Premove),
+            "/leave" => Ok(ClientCommand::Leave(argument.map(str::to_owned))),
+            "/list" => Ok(ClientCommand::ListRooms),
+            "/games" => Ok(ClientCommand::ListGames),
+            "/whoami" => Ok(ClientCommand::WhoAmI),
+            "/orientation" => parse_color(&required("orientation")?)
+                .map(ClientCommand::Orientation)
+                .ok_or(ParseError::InvalidArgument("orientation")),
+            "/say" | "/say_players" | "/say_spectators" => {
+                let scope = match name {
+                    "/say_players" => ChatScope::Players,
+                    "/say_spectators" => ChatScope::Spectators,
+                    _ => ChatScope::All,
+                };
+                required("text").map(|text| ClientCommand::Say { scope, text })
+            }
+            "/mute_spectators" => Ok(ClientCommand::MuteSpectators(true)),
+            "/unmute_spectators" => Ok(ClientCommand::MuteSpectators(false)),
+            "/turn" => Ok(ClientCommand::Turn),
+            "/status" => Ok(ClientCommand::Status),
+            "/stats" => Ok(ClientCommand::Stats),
+            "/players" => Ok(ClientCommand::Players(argument.map(str::to_owned))),
+            "/moves" => Square::parse(&required("square")?)
+                .map(ClientCommand::Moves)
+                .ok_or(ParseError::InvalidArgument("square")),
+            "/history" => Ok(ClientCommand::History),
+            "/pgn" => Ok(ClientCommand::Pgn),
+            "/rating" => required("name").map(ClientCommand::Rating),
+            "/archive" => required("game id")?
+                .parse()
+                .map(ClientCommand::Archive)
+                .map_err(|_| ParseError::InvalidArgument("game id")),
+            "/replay" => {
+                let argument = required("game id")?;
+                let (id, speed) = match argument.split_once(' ') {
+                    Some((id, speed)) => (id, Some(speed.trim())),
+                    None => (argument.as_str(), None),
+                };
+                Ok(ClientCommand::Replay {
+                    id: id
+                        .parse()
+                        .map_err(|_| ParseError::InvalidArgument("game id"))?,
+                    speed: match speed {
+                        Some(speed) => replay_speed(speed.parse().ok())
+                            .ok_or(ParseError::InvalidArgument("speed"))?,
+                        None => 1,
+                    },
+                })
+            }
+            "/replay_stop" => Ok(ClientCommand::ReplayStop),
+            "/resign" | "/give_up" => Ok(ClientCommand::Resign),
+            "/abort" => Ok(ClientCommand::Abort),
+            "/claim_draw" => Ok(ClientCommand::ClaimDraw),
+            "/offer_draw" => Ok(ClientCommand::OfferDraw),
+            "/accept_draw" => Ok(ClientCommand::AcceptDraw),
+            "/propose_time" => serde_json::from_str(&required("time control")?)
+                .map(ClientCommand::ProposeTime)
+                .map_err(|err| ParseError::InvalidConfig(err.to_string())),
+            "/accept_time" => Ok(ClientCommand::AcceptTime),
+            "/decline_draw" => Ok(ClientCommand::DeclineDraw),
+            "/draw_status" => Ok(ClientCommand::DrawStatus),
+            "/offer_takeback" => Ok(ClientCommand::OfferTakeback),
+            "/accept_takeback" => Ok(ClientCommand::AcceptTakeback),
+            "/berserk" => Ok(ClientCommand::Berserk),
+            "/restart_game" => Ok(ClientCommand::Rematch),
+            "/mirror" if DEBUG_COMMANDS => Ok(ClientCommand::Mirror),
+            "/perft" if DEBUG_COMMANDS => perft_depth(required("depth")?.parse().ok())
+                .map(ClientCommand::Perft)
+                .ok_or(ParseError::InvalidArgument("depth")),
+            "/restart" => Ok(ClientCommand::Rematch),
+            "/promote" => Ok(ClientCommand::Promote),
+            "/flag" => Ok(ClientCommand::Flag),
+            "/want_seat" => Ok(ClientCommand::WantSeat),
+            "/pass_seat" => required("spectator id")?
+                .parse()
+                .map(ClientCommand::PassSeat)
+                .map_err(|_| ParseError::InvalidArgument("spectator id")),
+            "/quickmatch" => Ok(ClientCommand::QuickMatch),
+            "/play_bot" => Ok(ClientCommand::PlayBot),
+            _ => Err(ParseError::UnknownCommand(text.to_owned())),
+        }
+    }
+}
+
+/// Номер полухода перед ходом в `/chess_step`
+fn parse_ply(number: &str) -> Result<usize, ParseError> {
+    number
+        .parse()
+        .ok()
+        .filter(|_| number.chars().all(|c| c.is_ascii_digit()))
+        .ok_or(ParseError::InvalidArgument("ply"))
+}
+
+/// Скорость показа партии, если она от 1 до `MAX_REPLAY_SPEED`
+pub fn replay_speed(speed: Option<u32>) -> Option<u32> {
+    speed.filter(|speed| (1..=MAX_REPLAY_SPEED).contains(speed))
+}
+
+/// Глубина `/perft`, если она от 1 до `MAX_PERFT_DEPTH`
+pub fn perft_depth(depth: Option<u32>) -> Option<u32> {
+    depth.filter(|depth| (1..=MAX_PERFT_DEPTH).contains(depth))
+}
+
+/// Цвет словом `white` или `black`
+pub fn parse_color(color: &str) -> Option<Color> {
+    match color.to_ascii_lowercase().as_str() {
+        "white" => Some(Color::White),
+        "black" => Some(Color::Black),
+        _ => None,
+    }
+}
+
+/// Ход в каноническом виде: строчные буквы без разделителей, например `e2e4`
+pub fn normalize_step(step: &str) -> Result<String, ParseError> {
+    Move::parse_lenient(step)
+        .map(|mv| mv.to_string())
+        .ok_or_else(|| ParseError::MalformedMove(step.to_owned()))
+}
+
+/// Ход для `/chess_step`: координатная запись приводится к каноническому виду, а запись
+/// в SAN остаётся как есть, ведь понять её можно только по позиции партии
+pub fn parse_step(step: &str) -> Result<String, ParseError> {
+    normalize_step(step).or_else(|err| {
+        if san::is_san(step) {
+            Ok(step.trim().to_owned())
+        } else {
+            Err(err)
+        }
+    })
+}
+
+/// Имя попадает в сообщения чата и списки комнат, поэтому оно должно быть коротким
+/// и не содержать переводов строк и других управляющих символов
+pub fn validate_name(name: String, what: &'static str) -> Result<String, ParseError> {
+    if name.chars().count() > MAX_NAME_LENGTH || name.chars().any(char::is_control) {
+        Err(ParseError::InvalidName(what))
+    } else {
+        Ok(name)
+    }
+}
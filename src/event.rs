@@ -0,0 +1,300 @@
+//! События, которые сервер отправляет клиентам. Каждое событие сериализуется в JSON-объект
+//! с полем `type`, например `{"type":"move","move":"e2e4"}`.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    board::{Color, PieceKind},
+    clock::ClockConfig,
+    game::{GameId, Outcome},
+};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// Токен, с которым клиент может переподключиться к своей сессии
+    Session { token: Uuid },
+    /// Среднее время ответа клиента на пинг в миллисекундах
+    Ping { rtt_ms: u128 },
+    /// Служебное текстовое сообщение: подключения и ответы на команды
+    System { text: String },
+    /// Отказ выполнить команду: `code` не меняется и подходит для программ, `message`
+    /// объясняет отказ человеку
+    Error { code: String, message: String },
+    /// Сессия вошла в комнату
+    Join { id: usize, name: Option<String> },
+    /// Отключившийся игрок переподключился вовремя и снова на своём месте
+    Reconnected { id: usize },
+    /// Сессия вышла из комнаты или отключилась
+    Leave { id: usize, name: Option<String> },
+    /// Соперник отключился: `clean`, если он сам закрыл соединение, а не потерял связь
+    OpponentLeft { clean: bool },
+    /// Сообщение чата
+    Chat { text: String },
+    /// Клиент не успевал забирать сообщения, и столько сообщений чата ему не отправлено
+    Lag { dropped: usize },
+    /// Количество посетителей сервера
+    Visitors { count: usize },
+    /// Сводка по всему серверу: подключённые сессии, комнаты с партиями, идущие
+    /// и законченные партии, посетители
+    Stats {
+        sessions: usize,
+        game_rooms: usize,
+        games_in_progress: usize,
+        games_finished: usize,
+        visitors: usize,
+    },
+    /// Список комнат
+    Rooms { rooms: Vec<String> },
+    /// Партии игровых комнат
+    Games { games: Vec<GameSummary> },
+    /// Ход соперника в координатной нотации
+    Move {
+        #[serde(rename = "move")]
+        mv: String,
+        from: String,
+        to: String,
+        san: String,
+        /// Фигура, в которую превратилась пешка
+        promotion: Option<PieceKind>,
+        /// Ход ладьи при рокировке
+        rook: Option<SquareMove>,
+        /// Поле пешки, взятой на проходе
+        captured: Option<String>,
+        /// Когда сделан ход, в миллисекундах от начала партии
+        elapsed_ms: u128,
+    },
+    /// Ход сессии принят; `ply` — его номер в партии, считая с единицы
+    MoveAck {
+        #[serde(rename = "move")]
+        mv: String,
+        ply: usize,
+    },
+    /// Допустим ли ход в текущей позиции и, если нет, почему
+    MoveCheck {
+        #[serde(rename = "move")]
+        mv: String,
+        legal: bool,
+        reason: Option<String>,
+    },
+    /// Подсказка: ход, который перебор считает лучшим для стороны, которая ходит
+    Hint {
+        #[serde(rename = "move")]
+        mv: String,
+    },
+    /// Сколько позиций получается из текущей за `depth` полуходов
+    Perft { depth: u32, nodes: u64 },
+    /// Для сессии нашёлся соперник: она перешла в комнату новой партии
+    Matched { room: String, color: Color },
+    /// Сведения о самой сессии
+    Whoami {
+        id: usize,
+        room: String,
+        name: Option<String>,
+        color: Option<Color>,
+        role: Option<String>,
+        orientation: Option<Color>,
+    },
+    /// Какой стороной к себе сессия хочет видеть доску
+    Orientation { orientation: Color },
+    /// Цвет, за который сессия играет в комнате
+    Color { color: Color },
+    /// Роль сессии в комнате, если она не играет
+    Role { role: String },
+    /// Имена игроков партии и число зрителей
+    Players {
+        white: Option<String>,
+        black: Option<String>,
+        spectators: usize,
+    },
+    /// За доску сели оба игрока: партия началась, и у белых пошли часы
+    GameStart {
+        white: Option<String>,
+        black: Option<String>,
+        fen: String,
+    },
+    /// Зритель из очереди `/want_seat` занял место отключившегося игрока
+    Promoted { color: Color },
+    /// Игрок `from` уступил своё место за `color` зрителю `to`
+    SeatPassed {
+        color: Color,
+        from: usize,
+        to: usize,
+    },
+    /// Сколько зрителей смотрит партию комнаты
+    Spectators { count: usize },
+    /// Поля, на которые может пойти фигура с поля `from`
+    Moves { from: String, to: Vec<String> },
+    /// Ходы партии в SAN
+    History { moves: Vec<String> },
+    /// Партия в PGN
+    Pgn { data: String },
+    /// Текущая позиция партии в FEN
+    Position { fen: String },
+    /// Сторона, которая сейчас ходит
+    Turn { color: Color },
+    /// Номер хода, полуходы с последнего взятия или хода пешкой, очередь хода, результат
+    /// законченной партии и сколько раз каждой стороне ещё можно вернуть ход
+    Status {
+        fullmove: u32,
+        halfmove: u32,
+        turn: Color,
+        result: Option<String>,
+        white_takebacks_left: u32,
+        black_takebacks_left: u32,
+    },
+    /// Фигуры, взятые каждой стороной, буквами из FEN: `["p","n"]`
+    Material {
+        white_captured: Vec<char>,
+        black_captured: Vec<char>,
+    },
+    /// Королю стороны объявлен шах
+    Check { side: Color },
+    /// Партия окончена
+    Gameover {
+        reason: String,
+        winner: Option<Color>,
+    },
+    /// Законченная партия комнаты сохранена в архив под этим номером
+    Archived { id: GameId },
+    /// Партия из архива
+    ArchivedGame {
+        id: GameId,
+        white: Option<String>,
+        black: Option<String>,
+        result: String,
+        reason: String,
+        moves: Vec<String>,
+        /// Стороны, которые пошли в берсерк
+        berserk: Vec<Color>,
+    },
+    /// Начало показа партии из архива: её начальная позиция
+    Replay { id: GameId, fen: String },
+    /// Очередной ход показываемой партии и позиция после него
+    ReplayMove {
+        ply: usize,
+        san: String,
+        fen: String,
+    },
+    /// Показ партии из архива закончен
+    ReplayEnd { id: GameId },
+    /// Новые рейтинги игроков после партии
+    Ratings { ratings: BTreeMap<String, f64> },
+    /// Рейтинг игрока
+    Rating { name: String, rating: f64 },
+    /// Оставшееся время сторон в миллисекундах
+    Clock { white: u128, black: u128 },
+    /// Отложенный ход оказался недопустим в новой позиции и отменён
+    PremoveCancelled,
+    /// Соперник предлагает до первого хода сменить контроль времени; `null` — партия
+    /// без часов
+    TimeOffered { time_control: Option<TimeSettings> },
+    /// Игроки договорились о новом контроле времени, часы сброшены
+    TimeChanged { time_control: Option<TimeSettings> },
+    /// Сторона пошла в берсерк: её время уменьшено вдвое, добавки за ход у неё нет
+    Berserk { color: Color },
+    /// Соперник предлагает ничью
+    DrawOffered,
+    /// Соперник отклонил предложенную ничью
+    DrawDeclined,
+    /// Есть ли в партии предложение ничьей и какая сторона его сделала
+    DrawStatus { pending: bool, by: Option<Color> },
+    /// Соперник предлагает вернуть последний ход
+    TakebackOffered,
+    /// Соперник хочет сыграть ещё одну партию
+    RematchOffered,
+    /// Последний ход партии возвращён
+    Takeback,
+    /// Партия начата заново
+    Restart,
+}
+
+/// Партия комнаты в списке партий
+#[derive(Debug, Clone, Serialize)]
+pub struct GameSummary {
+    pub room: String,
+    pub id: GameId,
+    pub white: Option<String>,
+    pub black: Option<String>,
+    /// `in_progress` или `finished`
+    pub status: &'static str,
+    /// Результат законченной партии
+    pub result: Option<String>,
+    /// Сколько сделано полуходов
+    pub moves: usize,
+    pub spectators: usize,
+    /// Нужен ли пароль, чтобы войти в комнату
+    pub private: bool,
+}
+
+/// Контроль времени в секундах, как его задают в настройках партии
+#[derive(Debug, Clone, Serialize)]
+pub struct TimeSettings {
+    pub white_initial: u64,
+    pub black_initial: u64,
+    pub white_increment: u64,
+    pub black_increment: u64,
+    /// Время задано не на партию, а на каждый ход, как в партии по переписке
+    pub per_move: bool,
+}
+
+impl From<ClockConfig> for TimeSettings {
+    fn from(config: ClockConfig) -> TimeSettings {
+        TimeSettings {
+            white_initial: config.white_initial.as_secs(),
+            black_initial: config.black_initial.as_secs(),
+            white_increment: config.white_increment.as_secs(),
+            black_increment: config.black_increment.as_secs(),
+            per_move: config.per_move,
+        }
+    }
+}
+
+/// Перемещение фигуры с поля на поле
+#[derive(Debug, Clone, Serialize)]
+pub struct SquareMove {
+    pub from: String,
+    pub to: String,
+}
+
+impl ServerEvent {
+    pub fn system(text: impl Into<String>) -> ServerEvent {
+        ServerEvent::System { text: text.into() }
+    }
+
+    pub fn error(code: &str, message: impl Into<String>) -> ServerEvent {
+        ServerEvent::Error {
+            code: code.to_owned(),
+            message: message.into(),
+        }
+    }
+
+    /// Сообщение чата, которое можно не доставить медленному клиенту
+    pub fn is_chat(&self) -> bool {
+        matches!(self, ServerEvent::Chat { .. })
+    }
+
+    /// Ход соперника в записи UCI, если событие — ход
+    pub fn uci(&self) -> Option<&str> {
+        match self {
+            ServerEvent::Move { mv, .. } => Some(mv),
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+impl From<Outcome> for ServerEvent {
+    fn from(outcome: Outcome) -> ServerEvent {
+        ServerEvent::Gameover {
+            reason: outcome.reason().to_owned(),
+            winner: outcome.winner(),
+        }
+    }
+}
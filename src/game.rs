@@ -0,0 +1,643 @@
+//! Состояние партии в комнате: доска и сессии, которые играют за каждую сторону.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{Board, Color, Move, Piece, Square},
+    clock::{Clock, ClockConfig},
+    event::ServerEvent,
+    rules, san, search,
+};
+
+/// Сколько сессий могут играть в партии; остальные становятся зрителями
+const MAX_PLAYERS: usize = 2;
+
+/// Уникальный номер партии на сервере
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(transparent)]
+pub struct GameId(pub u64);
+
+impl fmt::Display for GameId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for GameId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(text: &str) -> Result<GameId, Self::Err> {
+        text.parse().map(GameId)
+    }
+}
+
+/// Разновидность шахмат: от неё зависит начальная позиция
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Variant {
+    #[default]
+    Standard,
+    /// Шахматы Фишера со случайной расстановкой
+    Chess960,
+}
+
+/// Сколько времени получает зритель, который занял место отключившегося игрока
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeatClock {
+    /// Время, оставшееся у ушедшего игрока
+    #[default]
+    Inherit,
+    /// Всё время стороны на партию, как в начале
+    Fresh,
+}
+
+/// Настройки партии, которые задаются при создании комнаты, например
+/// `{"variant":"chess960","time_control":{"initial":300,"increment":2},"rated":false}`.
+/// Пропущенные поля берутся по умолчанию, а `"time_control":null` означает партию без часов.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GameConfig {
+    pub variant: Variant,
+    /// Контроль времени; без него часы не идут
+    pub time_control: Option<ClockConfig>,
+    /// Меняет ли итог партии рейтинги игроков
+    pub rated: bool,
+    /// Сколько раз каждый игрок может вернуть свой ход за партию
+    pub takeback_limit: u32,
+    /// Время зрителя, который из очереди `/want_seat` занял место отключившегося игрока
+    pub seat_clock: SeatClock,
+    /// Сколько полуходов должно пройти, прежде чем игрок снова сможет предложить ничью
+    pub draw_offer_cooldown: u32,
+    /// Ход принимается, только если в нём указан номер полухода, под которым он войдёт
+    /// в партию
+    pub strict_ply: bool,
+    /// Заканчивать ли партию ничьей после пятидесяти ходов без взятий и ходов пешками
+    /// сразу; иначе ничью нужно потребовать командой `/claim_draw`
+    pub automatic_fifty_move: bool,
+}
+
+impl Default for GameConfig {
+    fn default() -> GameConfig {
+        GameConfig {
+            variant: Variant::Standard,
+            time_control: Some(ClockConfig::default()),
+            rated: true,
+            takeback_limit: 3,
+            seat_clock: SeatClock::Inherit,
+            draw_offer_cooldown: 3,
+            strict_ply: false,
+            automatic_fifty_move: true,
+        }
+    }
+}
+
+/// Чем закончилась партия
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Мат, указан победитель
+    Checkmate { winner: Color },
+    /// Игрок сдался, указан сдавшийся
+    Resign { loser: Color },
+    /// У игрока закончилось время, указан проигравший
+    Timeout { loser: Color },
+    /// Ничья: пат
+    Stalemate,
+    /// Ничья по правилу пятидесяти ходов
+    FiftyMove,
+    /// Ничья: позиция повторилась трижды
+    Repetition,
+    /// Ничья: ни одной из сторон нечем поставить мат
+    InsufficientMaterial,
+    /// Ничья: пешки заперты, и ни одна из сторон уже не сможет поставить мат
+    DeadPosition,
+    /// Ничья по соглашению игроков
+    Agreement,
+    /// Партия прервана без результата, например при остановке сервера
+    Aborted,
+    /// Партия прервана без результата: в комнате слишком долго ничего не происходило
+    Inactivity,
+}
+
+impl Outcome {
+    /// Причина окончания партии
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Outcome::Checkmate { .. } => "checkmate",
+            Outcome::Resign { .. } => "resign",
+            Outcome::Timeout { .. } => "timeout",
+            Outcome::Stalemate => "stalemate",
+            Outcome::FiftyMove => "fifty_move",
+            Outcome::Repetition => "repetition",
+            Outcome::InsufficientMaterial => "insufficient_material",
+            Outcome::DeadPosition => "dead_position",
+            Outcome::Agreement => "agreement",
+            Outcome::Aborted => "aborted",
+            Outcome::Inactivity => "inactivity",
+        }
+    }
+
+    /// Прервана ли партия, так и не получив результата
+    pub fn is_aborted(&self) -> bool {
+        matches!(self, Outcome::Aborted | Outcome::Inactivity)
+    }
+
+    /// Результат для PGN: `1-0`, `0-1`, `1/2-1/2` или `*` у прерванной партии
+    pub fn result(&self) -> &'static str {
+        if self.is_aborted() {
+            return "*";
+        }
+        match self.winner() {
+            Some(Color::White) => "1-0",
+            Some(Color::Black) => "0-1",
+            None => "1/2-1/2",
+        }
+    }
+
+    /// Победившая сторона
+    pub fn winner(&self) -> Option<Color> {
+        match *self {
+            Outcome::Checkmate { winner } => Some(winner),
+            Outcome::Resign { loser } | Outcome::Timeout { loser } => Some(loser.opposite()),
+            Outcome::Stalemate
+            | Outcome::FiftyMove
+            | Outcome::Repetition
+            | Outcome::InsufficientMaterial
+            | Outcome::DeadPosition
+            | Outcome::Agreement
+            | Outcome::Aborted
+            | Outcome::Inactivity => None,
+        }
+    }
+}
+
+/// Сколько фигур стоит на доске
+fn pieces(board: &Board) -> usize {
+    Square::all()
+        .filter(|&square| board.piece_at(square).is_some())
+        .count()
+}
+
+/// Счётчик повторений для отражённых позиций
+fn mirror_repetitions(repetitions: &HashMap<String, u32>) -> HashMap<String, u32> {
+    repetitions
+        .iter()
+        .filter_map(|(key, &count)| {
+            // ключ — первые четыре поля FEN, недостающие поля не влияют на ключ
+            let board = Board::from_fen(&format!("{} 0 1", key)).ok()?;
+            Some((board.mirror().position_key(), count))
+        })
+        .collect()
+}
+
+/// Когда сделан ход и сколько времени осталось у сходившей стороны
+#[derive(Debug, Clone, Copy)]
+pub struct MoveTime {
+    /// Время от начала партии
+    pub elapsed: Duration,
+    /// Время на часах сходившей стороны после хода, если часы шли
+    pub clock: Option<Duration>,
+    /// Ход был сделан заранее и сделан сразу после хода соперника
+    pub premove: bool,
+}
+
+#[derive(Debug)]
+pub struct Game {
+    pub id: GameId,
+    /// Позиция, с которой партия началась
+    pub start: Board,
+    pub board: Board,
+    /// Сессия, играющая белыми
+    pub white: Option<usize>,
+    /// Сессия, играющая чёрными
+    pub black: Option<usize>,
+    /// Результат партии; пока он не задан, партия продолжается
+    pub outcome: Option<Outcome>,
+    /// Сделанные ходы в SAN
+    pub history: Vec<String>,
+    /// Время каждого хода из `history`
+    pub times: Vec<MoveTime>,
+    /// Фигуры чёрных, взятые белыми
+    pub captured_white: Vec<Piece>,
+    /// Фигуры белых, взятые чёрными
+    pub captured_black: Vec<Piece>,
+    /// Сколько раз встречалась каждая позиция с последнего взятия или хода пешкой
+    repetitions: HashMap<String, u32>,
+    /// Доска и счётчик повторений перед каждым сделанным ходом, чтобы ход можно было вернуть
+    previous: Vec<(Board, HashMap<String, u32>)>,
+    /// Сторона, предложившая вернуть последний ход
+    pub takeback_offer: Option<Color>,
+    /// Сколько раз каждой стороне вернули ход
+    takebacks: HashMap<Color, u32>,
+    /// Стороны, которые пошли в берсерк: отдали половину времени за очко сверху при победе
+    pub berserk: HashSet<Color>,
+    /// Сторона, предложившая ничью
+    pub draw_offer: Option<Color>,
+    /// Полуход, на котором каждая сторона последний раз предлагала ничью
+    draw_offered_at: HashMap<Color, usize>,
+    /// Ход, который сторона заранее сделала за соперника очередь: он проверяется
+    /// и делается, как только очередь перейдёт к ней
+    pub premove: Option<(Color, Move)>,
+    /// Сторона, которая после конца партии хочет сыграть ещё одну
+    pub rematch_offer: Option<Color>,
+    /// Сторона, предложившая до первого хода другой контроль времени, и сам контроль;
+    /// `None` вместо контроля означает партию без часов
+    pub time_offer: Option<(Color, Option<ClockConfig>)>,
+    /// За доску сели оба игрока, и партия началась; если до первого хода кто-то из них
+    /// уйдёт, партия снова ждёт игрока
+    begun: bool,
+    /// Когда началась партия
+    pub started: SystemTime,
+    pub config: GameConfig,
+    pub clock: Clock,
+}
+
+impl Game {
+    pub fn new(id: GameId, start: Board, config: GameConfig) -> Game {
+        let repetitions = HashMap::from([(start.position_key(), 1)]);
+        Game {
+            id,
+            board: start.clone(),
+            start,
+            white: None,
+            black: None,
+            outcome: None,
+            history: Vec::new(),
+            times: Vec::new(),
+            captured_white: Vec::new(),
+            captured_black: Vec::new(),
+            repetitions,
+            previous: Vec::new(),
+            takeback_offer: None,
+            takebacks: HashMap::new(),
+            berserk: HashSet::new(),
+            draw_offer: None,
+            draw_offered_at: HashMap::new(),
+            premove: None,
+            rematch_offer: None,
+            time_offer: None,
+            begun: false,
+            started: SystemTime::now(),
+            config,
+            clock: Clock::new(config.time_control.unwrap_or_default()),
+        }
+    }
+
+    /// Вернуть доску в начальную позицию, не пересаживая игроков. Новая партия получает
+    /// новый номер.
+    pub fn restart(&mut self, id: GameId, now: Instant) {
+        self.id = id;
+        self.board = self.start.clone();
+        self.outcome = None;
+        self.history.clear();
+        self.times.clear();
+        self.captured_white.clear();
+        self.captured_black.clear();
+        self.repetitions = HashMap::from([(self.start.position_key(), 1)]);
+        self.previous.clear();
+        self.takeback_offer = None;
+        self.takebacks.clear();
+        self.berserk.clear();
+        self.draw_offer = None;
+        self.draw_offered_at.clear();
+        self.premove = None;
+        self.rematch_offer = None;
+        self.time_offer = None;
+        self.begun = false;
+        self.started = SystemTime::now();
+        self.clock = Clock::new(self.config.time_control.unwrap_or_default());
+        self.begin(now);
+    }
+
+    /// Отразить текущую позицию, как в `Board::mirror`. Партия продолжается: ходы и время
+    /// остаются, взятые фигуры и позиции для повторений и возврата хода отражаются вместе
+    /// с доской, а часы переходят к стороне, которая теперь ходит. Отложенный ход
+    /// отменяется.
+    pub fn mirror(&mut self, now: Instant) {
+        self.board = self.board.mirror();
+        self.repetitions = mirror_repetitions(&self.repetitions);
+        for (board, repetitions) in &mut self.previous {
+            *board = board.mirror();
+            *repetitions = mirror_repetitions(repetitions);
+        }
+        std::mem::swap(&mut self.captured_white, &mut self.captured_black);
+        for piece in self
+            .captured_white
+            .iter_mut()
+            .chain(&mut self.captured_black)
+        {
+            piece.color = piece.color.opposite();
+        }
+        self.premove = None;
+
+        if self.clock.is_running() {
+            self.clock.stop(now);
+            self.clock.start(self.turn(), now);
+        }
+    }
+
+    /// Сыграть ещё одну партию теми же игроками: они меняются цветами, и доска
+    /// возвращается в начальную позицию
+    pub fn rematch(&mut self, id: GameId, now: Instant) {
+        std::mem::swap(&mut self.white, &mut self.black);
+        self.restart(id, now);
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.outcome.is_some()
+    }
+
+    /// Начать партию, когда за доску сели оба игрока: запускаются часы стороны, которая ходит,
+    /// если у партии есть контроль времени. `true`, если партия началась именно сейчас.
+    pub fn begin(&mut self, now: Instant) -> bool {
+        if self.white.is_none() || self.black.is_none() || self.is_over() {
+            return false;
+        }
+        if self.config.time_control.is_some() && !self.clock.is_running() {
+            self.clock.start(self.turn(), now);
+        }
+        !std::mem::replace(&mut self.begun, true)
+    }
+
+    /// Сменить контроль времени: у обеих сторон снова всё время нового контроля, берсерк
+    /// отменяется. Если за доской оба игрока, часы сразу идут.
+    pub fn set_time_control(&mut self, time_control: Option<ClockConfig>, now: Instant) {
+        self.config.time_control = time_control;
+        self.clock = Clock::new(time_control.unwrap_or_default());
+        self.berserk.clear();
+        self.time_offer = None;
+        self.begin(now);
+    }
+
+    /// Закончить партию с указанным результатом и остановить часы
+    pub fn finish(&mut self, outcome: Outcome, now: Instant) {
+        self.outcome = Some(outcome);
+        self.clock.stop(now);
+    }
+
+    /// Краткое состояние партии: номер хода, счётчик полуходов, очередь и результат
+    pub fn status(&self) -> ServerEvent {
+        ServerEvent::Status {
+            fullmove: self.board.fullmove_number(),
+            halfmove: self.board.halfmove_clock(),
+            turn: self.turn(),
+            result: self.outcome.map(|outcome| outcome.result().to_owned()),
+            white_takebacks_left: self.takebacks_left(Color::White),
+            black_takebacks_left: self.takebacks_left(Color::Black),
+        }
+    }
+
+    /// Фигуры, взятые каждой стороной
+    pub fn material(&self) -> ServerEvent {
+        let letters = |pieces: &[Piece]| {
+            pieces
+                .iter()
+                .map(|piece| piece.to_char().to_ascii_lowercase())
+                .collect()
+        };
+        ServerEvent::Material {
+            white_captured: letters(&self.captured_white),
+            black_captured: letters(&self.captured_black),
+        }
+    }
+
+    /// Фигуры, которые взяла сторона
+    fn captured_mut(&mut self, color: Color) -> &mut Vec<Piece> {
+        match color {
+            Color::White => &mut self.captured_white,
+            Color::Black => &mut self.captured_black,
+        }
+    }
+
+    /// Сколько ещё раз стороне можно вернуть ход в этой партии
+    pub fn takebacks_left(&self, color: Color) -> u32 {
+        let used = self.takebacks.get(&color).copied().unwrap_or(0);
+        self.config.takeback_limit.saturating_sub(used)
+    }
+
+    /// Сделала ли сторона хотя бы один ход
+    pub fn has_moved(&self, color: Color) -> bool {
+        let before = if color == self.start.side_to_move() {
+            0
+        } else {
+            1
+        };
+        self.history.len() > before
+    }
+
+    /// Может ли сторона сейчас предложить ничью: с её прошлого предложения должно пройти
+    /// `draw_offer_cooldown` полуходов
+    pub fn can_offer_draw(&self, color: Color) -> bool {
+        let cooldown = self.config.draw_offer_cooldown as usize;
+        self.draw_offered_at
+            .get(&color)
+            .is_none_or(|&ply| self.history.len() >= ply + cooldown)
+    }
+
+    /// Предложить ничью от имени стороны
+    pub fn offer_draw(&mut self, color: Color) {
+        self.draw_offer = Some(color);
+        self.draw_offered_at.insert(color, self.history.len());
+    }
+
+    /// Оставшееся время обеих сторон
+    pub fn clock_status(&self, now: Instant) -> ServerEvent {
+        ServerEvent::Clock {
+            white: self.clock.remaining(Color::White, now).as_millis(),
+            black: self.clock.remaining(Color::Black, now).as_millis(),
+        }
+    }
+
+    /// Сделать ход: он записывается в историю вместе со временем, а новая позиция
+    /// учитывается при подсчёте повторений. Часы переключаются на соперника. Ход должен
+    /// быть допустим.
+    pub fn make_move(&mut self, mv: Move, now: Instant) {
+        self.previous
+            .push((self.board.clone(), self.repetitions.clone()));
+        self.takeback_offer = None;
+        self.time_offer = None;
+        self.history.push(san::to_san(&self.board, &mv));
+
+        let side = self.turn();
+        if let Some(piece) = self.board.captured_piece(&mv) {
+            self.captured_mut(side).push(piece);
+        }
+        let running = self.clock.is_running();
+        self.clock.switch(now);
+        self.times.push(MoveTime {
+            elapsed: self.started.elapsed().unwrap_or_default(),
+            clock: running.then(|| self.clock.remaining(side, now)),
+            premove: false,
+        });
+
+        self.board.apply(mv);
+
+        // после взятия или хода пешкой прежние позиции повториться уже не могут
+        if self.board.halfmove_clock() == 0 {
+            self.repetitions.clear();
+        }
+        *self
+            .repetitions
+            .entry(self.board.position_key())
+            .or_default() += 1;
+    }
+
+    /// Сделать ход, который игрок выбрал заранее, пока ходил соперник. Он делается так же,
+    /// как обычный, но помечается: скорость такого ответа ничего не говорит об игроке.
+    pub fn make_premove(&mut self, mv: Move, now: Instant) {
+        self.make_move(mv, now);
+        if let Some(time) = self.times.last_mut() {
+            time.premove = true;
+        }
+    }
+
+    /// Вернуть последний ход: доска возвращается в позицию до него, и часы снова идут
+    /// у сходившей стороны. Возврат засчитывается стороне, которая его попросила.
+    /// `false`, если ходов ещё не было.
+    pub fn take_back(&mut self, now: Instant) -> bool {
+        let (board, repetitions) = match self.previous.pop() {
+            Some(previous) => previous,
+            None => return false,
+        };
+        // если ход что-то взял, взятая фигура возвращается на доску и пропадает из списка
+        if pieces(&board) > pieces(&self.board) {
+            self.captured_mut(board.side_to_move()).pop();
+        }
+        self.board = board;
+        self.repetitions = repetitions;
+        self.history.pop();
+        self.times.pop();
+        if let Some(color) = self.takeback_offer.take() {
+            *self.takebacks.entry(color).or_default() += 1;
+        }
+        self.premove = None;
+
+        if self.clock.is_running() {
+            self.clock.stop(now);
+            self.clock.start(self.turn(), now);
+        }
+        true
+    }
+
+    /// Самая длинная серия ходов стороны, каждый из которых сделан быстрее `limit` после
+    /// хода соперника и совпал с лучшим ходом перебора на `depth` полуходов. Первый ход
+    /// партии, ходы, единственные в позиции, и заранее сделанные ходы не считаются: они
+    /// серию не продолжают и не прерывают.
+    pub fn fast_reply_streak(&self, color: Color, limit: Duration, depth: u32) -> usize {
+        let mut longest = 0;
+        let mut streak = 0;
+        for ply in 1..self.times.len() {
+            let board = &self.previous[ply].0;
+            if board.side_to_move() != color
+                || self.times[ply].premove
+                || rules::legal_moves(board, color).len() == 1
+            {
+                continue;
+            }
+            let reply = self.times[ply]
+                .elapsed
+                .saturating_sub(self.times[ply - 1].elapsed);
+            // перебор дорогой, поэтому с лучшим ходом сравниваются только быстрые ответы
+            let engine_move = || {
+                search::best_move(board, depth)
+                    .is_some_and(|best| san::to_san(board, &best) == self.history[ply])
+            };
+            if reply < limit && engine_move() {
+                streak += 1;
+                longest = longest.max(streak);
+            } else {
+                streak = 0;
+            }
+        }
+        longest
+    }
+
+    /// Сессия, играющая за сторону
+    pub fn player(&self, color: Color) -> Option<usize> {
+        match color {
+            Color::White => self.white,
+            Color::Black => self.black,
+        }
+    }
+
+    /// Сколько раз встречалась текущая позиция
+    pub fn repetition_count(&self) -> u32 {
+        self.repetitions
+            .get(&self.board.position_key())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Сторона, которая должна сделать следующий ход
+    pub fn turn(&self) -> Color {
+        self.board.side_to_move()
+    }
+
+    /// Цвет, за который играет сессия
+    pub fn color_of(&self, id: usize) -> Option<Color> {
+        if self.white == Some(id) {
+            Some(Color::White)
+        } else if self.black == Some(id) {
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// Сколько мест за доской занято
+    pub fn player_count(&self) -> usize {
+        [self.white, self.black].iter().flatten().count()
+    }
+
+    /// Посадить сессию за свободную сторону: первая севшая играет белыми, вторая чёрными.
+    /// Если обе стороны заняты, сессия остаётся зрителем и возвращается `None`.
+    pub fn seat(&mut self, id: usize) -> Option<Color> {
+        if let Some(color) = self.color_of(id) {
+            return Some(color);
+        }
+        if self.player_count() >= MAX_PLAYERS {
+            return None;
+        }
+
+        if self.white.is_none() {
+            self.white = Some(id);
+            Some(Color::White)
+        } else if self.black.is_none() {
+            self.black = Some(id);
+            Some(Color::Black)
+        } else {
+            None
+        }
+    }
+
+    /// Освободить место сессии, если она играла в этой партии. Если ходов ещё не было,
+    /// часы останавливаются, и партия снова ждёт второго игрока.
+    pub fn leave(&mut self, id: usize, now: Instant) {
+        if let Some(color) = self.color_of(id) {
+            // ушедший игрок больше не ждёт новой партии
+            if self.rematch_offer == Some(color) {
+                self.rematch_offer = None;
+            }
+            if self.time_offer.is_some_and(|(side, _)| side == color) {
+                self.time_offer = None;
+            }
+            if self.history.is_empty() && !self.is_over() {
+                self.begun = false;
+                self.clock.stop(now);
+            }
+        }
+        if self.white == Some(id) {
+            self.white = None;
+        }
+        if self.black == Some(id) {
+            self.black = None;
+        }
+    }
+}
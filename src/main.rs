@@ -1,4 +1,6 @@
 use std::{
+    collections::HashSet,
+    env,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -9,29 +11,131 @@ use std::{
 use actix::*;
 use actix_files::{Files, NamedFile};
 use actix_web::{
-    middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    http::header, middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer,
+    Responder,
 };
 use actix_web_actors::ws;
+use serde::Deserialize;
 
+use rate_limit::TokenBucket;
+
+mod archive;
+mod board;
+mod bot;
+mod clock;
+mod command;
+mod event;
+mod game;
+mod pgn;
+mod protocol;
+mod rate_limit;
+mod rating;
+mod rules;
+mod san;
+mod search;
 mod server;
 mod session;
 
+/// Комната для новых сессий, если она не задана переменной окружения `CHESS_LOBBY`
+const DEFAULT_LOBBY: &str = "Main";
+
+/// Сколько комнат, считая лобби, сервер держит одновременно
+const MAX_ROOMS: usize = 10_000;
+
+/// Переменная окружения с зерном случайности сервера: с ним партии с ботом повторяются
+const SEED_VARIABLE: &str = "CHESS_SEED";
+
 async fn index() -> impl Responder {
-    NamedFile::open_async("./../frontend/index.html").await.unwrap()
+    NamedFile::open_async("./../frontend/index.html")
+        .await
+        .unwrap()
+}
+
+/// Сайты, страницам которых можно открывать вебсокет. Задаются переменной окружения
+/// `CHESS_ALLOWED_ORIGINS` через запятую; `*` или пустая переменная разрешают любой сайт.
+enum AllowedOrigins {
+    Any,
+    List(HashSet<String>),
+}
+
+impl AllowedOrigins {
+    fn from_env() -> AllowedOrigins {
+        match env::var("CHESS_ALLOWED_ORIGINS") {
+            Ok(origins) if !origins.trim().is_empty() && origins.trim() != "*" => {
+                AllowedOrigins::List(
+                    origins
+                        .split(',')
+                        .map(|origin| origin.trim().trim_end_matches('/').to_owned())
+                        .filter(|origin| !origin.is_empty())
+                        .collect(),
+                )
+            }
+            _ => AllowedOrigins::Any,
+        }
+    }
+
+    /// Запрос без заголовка `Origin` пришёл не из браузера, и чужой сайт его отправить
+    /// не мог, поэтому он пропускается
+    fn allows(&self, origin: Option<&str>) -> bool {
+        match (self, origin) {
+            (AllowedOrigins::Any, _) | (_, None) => true,
+            (AllowedOrigins::List(origins), Some(origin)) => {
+                origins.contains(origin.trim_end_matches('/'))
+            }
+        }
+    }
+}
+
+/// Параметры подключения к вебсокету
+#[derive(Deserialize)]
+struct ChatQuery {
+    /// Токен прежней сессии, выданный при первом подключении
+    token: Option<String>,
 }
 
 /// Точка входа для нашего маршрута websocket
 async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
+    query: web::Query<ChatQuery>,
     srv: web::Data<Addr<server::ChatServer>>,
+    origins: web::Data<AllowedOrigins>,
 ) -> Result<HttpResponse, Error> {
+    // вебсокет не подчиняется политике одного источника, поэтому страницы чужих сайтов
+    // отсекаются здесь, до начала сессии
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .map(|origin| origin.to_str().unwrap_or_default());
+    if !origins.allows(origin) {
+        log::warn!("websocket from origin {:?} rejected", origin);
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    // негодный токен не мешает подключиться, сессия просто начнётся заново
+    let reconnect = query.token.as_deref().and_then(|token| token.parse().ok());
+
     ws::start(
         session::WsChatSession {
             id: 0,
             hb: Instant::now(),
-            room: "Main".to_owned(),
+            heartbeat_interval: session::HEARTBEAT_INTERVAL,
+            client_timeout: session::CLIENT_TIMEOUT,
+            // комнаты сессия узнаёт от сервера чата, когда подключится к нему
+            room: String::new(),
+            rooms: HashSet::new(),
+            lobby: String::new(),
             name: None,
+            orientation: None,
+            reconnect,
+            spectating: HashSet::new(),
+            latency: Default::default(),
+            fragments: Default::default(),
+            outbox: Default::default(),
+            engine_mode: false,
+            replay: None,
+            clean_close: false,
+            limiter: TokenBucket::new(session::MESSAGES_PER_SECOND, Instant::now()),
             addr: srv.get_ref().clone(),
         },
         &req,
@@ -45,6 +149,24 @@ async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
     format!("Visitors: {}", current_count)
 }
 
+/// Дождаться сигнала остановки: SIGTERM или Ctrl+C
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("cannot listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = actix_web::rt::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = actix_web::rt::signal::ctrl_c().await;
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
@@ -53,14 +175,26 @@ async fn main() -> std::io::Result<()> {
     let app_state = Arc::new(AtomicUsize::new(0));
 
     // запустить агент сервера чата
-    let server = server::ChatServer::new(app_state.clone()).start();
+    let lobby = env::var("CHESS_LOBBY").unwrap_or_else(|_| DEFAULT_LOBBY.to_owned());
+    let mut server = server::ChatServer::new(app_state.clone(), lobby, MAX_ROOMS);
+    if let Ok(seed) = env::var(SEED_VARIABLE) {
+        match seed.parse() {
+            Ok(seed) => server = server.with_seed(seed),
+            Err(_) => log::warn!("ignoring {}: {:?} is not a number", SEED_VARIABLE, seed),
+        }
+    }
+    let server = server.start();
 
     log::info!("starting HTTP server at http://localhost:8080");
 
-    HttpServer::new(move || {
+    let origins = web::Data::new(AllowedOrigins::from_env());
+
+    let chat_server = server.clone();
+    let http_server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::from(app_state.clone()))
-            .app_data(web::Data::new(server.clone()))
+            .app_data(origins.clone())
+            .app_data(web::Data::new(chat_server.clone()))
             .service(web::resource("/").to(index))
             .route("/count", web::get().to(get_count))
             .route("/ws", web::get().to(chat_route))
@@ -68,8 +202,23 @@ async fn main() -> std::io::Result<()> {
             .service(Files::new("/", "../frontend/icons"))
             .wrap(Logger::default())
     })
+    // сигналы обрабатываем сами, чтобы сначала предупредить игроков
+    .disable_signals()
     .workers(2)
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
-}
\ No newline at end of file
+    .run();
+
+    let http_handle = http_server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown_signal().await;
+        log::info!("shutting down");
+
+        if server.send(server::Shutdown).await.is_err() {
+            log::warn!("chat server did not reply to shutdown");
+        }
+        actix_web::rt::time::sleep(server::SHUTDOWN_NOTICE).await;
+        http_handle.stop(true).await;
+    });
+
+    http_server.await
+}
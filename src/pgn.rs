@@ -0,0 +1,224 @@
+//! Партия в PGN: пары тегов и ходы в SAN. Партию можно записать в PGN и прочитать обратно.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    board::{Board, Color, FenError, Move},
+    game::Game,
+    san::{self, SanError},
+};
+
+/// Максимальная длина строки с ходами
+const LINE_WIDTH: usize = 80;
+
+/// Записать партию в PGN. Имена игроков берутся из сессий, для незанятого места или
+/// безымянного игрока пишется `?`. Если часы шли, после каждого хода в комментарии
+/// `[%clk]` записывается время, оставшееся у сходившей стороны.
+pub fn to_pgn(game: &Game, site: &str, white: Option<&str>, black: Option<&str>) -> String {
+    let result = game.outcome.map_or("*", |outcome| outcome.result());
+
+    let date = format_date(game.started);
+    let mut tags = vec![
+        ("Event", "Casual game".to_owned()),
+        ("Site", site.to_owned()),
+        ("Date", date),
+        ("White", white.unwrap_or("?").to_owned()),
+        ("Black", black.unwrap_or("?").to_owned()),
+        ("Result", result.to_owned()),
+    ];
+    // партия не из начальной позиции записывается вместе с ней
+    if game.start != Board::new() {
+        tags.push(("SetUp", "1".to_owned()));
+        tags.push(("FEN", game.start.to_fen()));
+    }
+
+    let mut pgn = String::new();
+    for (name, value) in tags {
+        pgn.push_str(&format!("[{} \"{}\"]\n", name, escape(&value)));
+    }
+    pgn.push('\n');
+
+    let mut tokens = Vec::new();
+    let mut number = game.start.fullmove_number();
+    let mut side = game.start.side_to_move();
+    for (ply, san) in game.history.iter().enumerate() {
+        match side {
+            Color::White => tokens.push(format!("{}.", number)),
+            Color::Black if ply == 0 => tokens.push(format!("{}...", number)),
+            Color::Black => {}
+        }
+        if side == Color::Black {
+            number += 1;
+        }
+        side = side.opposite();
+        tokens.push(san.clone());
+        if let Some(clock) = game.times.get(ply).and_then(|time| time.clock) {
+            tokens.push(format!("{{[%clk {}]}}", format_clock(clock)));
+        }
+    }
+    tokens.push(result.to_owned());
+
+    let mut line = String::new();
+    for token in tokens {
+        if !line.is_empty() && line.len() + 1 + token.len() > LINE_WIDTH {
+            pgn.push_str(&line);
+            pgn.push('\n');
+            line.clear();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&token);
+    }
+    pgn.push_str(&line);
+    pgn.push('\n');
+    pgn
+}
+
+/// Почему PGN не удалось прочитать
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PgnError {
+    /// В теге `FEN` записана неверная позиция
+    Fen(FenError),
+    /// Ход не удалось сделать: номер хода, сторона и причина
+    Move {
+        number: u32,
+        side: Color,
+        error: SanError,
+    },
+}
+
+impl fmt::Display for PgnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PgnError::Fen(err) => write!(f, "invalid FEN tag: {}", err),
+            PgnError::Move {
+                number,
+                side,
+                error,
+            } => write!(f, "move {} ({}): {}", number, side, error),
+        }
+    }
+}
+
+/// Прочитать партию из PGN: начальную позицию из тега `FEN`, если он есть, и ходы основного
+/// варианта. Каждый ход проверяется в позиции, которая получилась после предыдущих.
+/// Комментарии, варианты, оценки `$n` и результат пропускаются.
+pub fn from_pgn(text: &str) -> Result<(Board, Vec<Move>), PgnError> {
+    let mut start = Board::new();
+    let mut movetext = String::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(tag) = line.strip_prefix('[') {
+            if let Some(fen) = tag_value(tag, "FEN") {
+                start = Board::from_fen(fen).map_err(PgnError::Fen)?;
+            }
+        } else if !line.starts_with('%') {
+            movetext.push_str(line);
+            movetext.push('\n');
+        }
+    }
+
+    let mut board = start.clone();
+    let mut moves = Vec::new();
+    for token in movetext_tokens(&movetext) {
+        let mv = san::parse_san(&board, &token).map_err(|error| PgnError::Move {
+            number: board.fullmove_number(),
+            side: board.side_to_move(),
+            error,
+        })?;
+        board.apply(mv);
+        moves.push(mv);
+    }
+    Ok((start, moves))
+}
+
+/// Значение тега `[name "value"]`, если тег называется `name`
+fn tag_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let (tag_name, value) = tag.trim_end().strip_suffix(']')?.split_once(' ')?;
+    (tag_name == name).then(|| value.trim().trim_matches('"'))
+}
+
+/// Ходы основного варианта без номеров ходов
+fn movetext_tokens(movetext: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut depth = 0;
+    let mut chars = movetext.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                chars.by_ref().find(|&c| c == '}');
+            }
+            ';' => {
+                chars.by_ref().find(|&c| c == '\n');
+            }
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ if depth > 0 => continue,
+            _ if !c.is_whitespace() => {
+                token.push(c);
+                continue;
+            }
+            _ => {}
+        }
+        tokens.push(std::mem::take(&mut token));
+    }
+    tokens.push(token);
+
+    tokens
+        .into_iter()
+        .filter_map(|token| {
+            // номер хода бывает слит с ходом: `12.e4`
+            let token = match token.rfind('.') {
+                Some(dot) if token[..dot].chars().all(|c| c.is_ascii_digit() || c == '.') => {
+                    token[dot + 1..].to_owned()
+                }
+                _ => token,
+            };
+            let skip = token.is_empty()
+                || token.starts_with('$')
+                || matches!(token.as_str(), "1-0" | "0-1" | "1/2-1/2" | "*");
+            (!skip).then_some(token)
+        })
+        .collect()
+}
+
+/// В значениях тегов кавычки и обратная косая черта экранируются
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Время на часах в формате `Ч:ММ:СС`
+fn format_clock(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Дата в формате PGN: `ГГГГ.ММ.ДД`
+fn format_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() / 86_400) as i64;
+
+    // перевод числа дней от 1970-01-01 в григорианскую дату
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}.{:02}.{:02}", year, month, day)
+}
@@ -0,0 +1,248 @@
+//! Разбор команд, которые клиент присылает JSON-объектами, например
+//! `{"type":"chess_step","move":"e2e4"}`. Поле `type` называет команду так же, как текстовая
+//! команда без `/`, остальные поля — её аргументы.
+
+use std::fmt;
+
+use serde_json::{Map, Value};
+
+use crate::{
+    board::Square,
+    command::{
+        normalize_step, parse_color, parse_step, perft_depth, replay_speed, validate_name,
+        ChatScope, ClientCommand, DEBUG_COMMANDS,
+    },
+    game::GameId,
+};
+
+/// Ошибка протокола: клиент прислал сообщение, которое сервер не может разобрать
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Текст не является JSON
+    InvalidJson(String),
+    /// JSON не является объектом
+    NotAnObject,
+    /// Нет обязательного поля
+    MissingField(&'static str),
+    /// Поле есть, но его значение не подходит
+    InvalidField(&'static str),
+    /// Неизвестный тип сообщения
+    UnknownType(String),
+    /// Сервер принимает только текстовые сообщения
+    BinaryFrame,
+    /// Сообщение, собранное из частей, больше допустимого
+    MessageTooLarge,
+    /// Сообщение, собранное из частей, не является текстом в UTF-8
+    InvalidUtf8,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::InvalidJson(err) => write!(f, "invalid json: {}", err),
+            ProtocolError::NotAnObject => write!(f, "message must be a json object"),
+            ProtocolError::MissingField(field) => write!(f, "missing field {:?}", field),
+            ProtocolError::InvalidField(field) => write!(f, "invalid field {:?}", field),
+            ProtocolError::UnknownType(kind) => write!(f, "unknown message type {:?}", kind),
+            ProtocolError::BinaryFrame => write!(f, "binary messages are not supported"),
+            ProtocolError::MessageTooLarge => write!(f, "message is too large"),
+            ProtocolError::InvalidUtf8 => write!(f, "message is not valid utf-8"),
+        }
+    }
+}
+
+/// Обязательное непустое строковое поле
+fn string(object: &Map<String, Value>, field: &'static str) -> Result<String, ProtocolError> {
+    match optional(object, field)? {
+        Some(value) if !value.is_empty() => Ok(value),
+        Some(_) => Err(ProtocolError::InvalidField(field)),
+        None => Err(ProtocolError::MissingField(field)),
+    }
+}
+
+/// Необязательное строковое поле; `null` считается отсутствием поля
+fn optional(
+    object: &Map<String, Value>,
+    field: &'static str,
+) -> Result<Option<String>, ProtocolError> {
+    match object.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value.trim().to_owned())),
+        Some(_) => Err(ProtocolError::InvalidField(field)),
+    }
+}
+
+/// Номер партии из поля `id`
+fn game_id(object: &Map<String, Value>) -> Result<GameId, ProtocolError> {
+    match object.get("id") {
+        Some(Value::Number(id)) => id
+            .as_u64()
+            .map(GameId)
+            .ok_or(ProtocolError::InvalidField("id")),
+        Some(_) => Err(ProtocolError::InvalidField("id")),
+        None => Err(ProtocolError::MissingField("id")),
+    }
+}
+
+/// Название комнаты из поля `room`
+fn room(object: &Map<String, Value>) -> Result<String, ProtocolError> {
+    validate_name(string(object, "room")?, "room name")
+        .map_err(|_| ProtocolError::InvalidField("room"))
+}
+
+/// Разобрать JSON-сообщение клиента
+pub fn parse_client_json(text: &str) -> Result<ClientCommand, ProtocolError> {
+    let value: Value =
+        serde_json::from_str(text).map_err(|err| ProtocolError::InvalidJson(err.to_string()))?;
+    let object = value.as_object().ok_or(ProtocolError::NotAnObject)?;
+    let kind = string(object, "type")?;
+
+    let command = match kind.as_str() {
+        "join" => ClientCommand::Join {
+            room: room(object)?,
+            password: optional(object, "password")?,
+        },
+        "create" => ClientCommand::Create {
+            room: room(object)?,
+            password: string(object, "password")?,
+        },
+        "spectate" => ClientCommand::Spectate {
+            room: room(object)?,
+            password: optional(object, "password")?,
+        },
+        "join_fen" => ClientCommand::JoinFen {
+            room: room(object)?,
+            fen: string(object, "fen")?,
+        },
+        "create_game" => ClientCommand::CreateGame {
+            room: room(object)?,
+            config: match object.get("config") {
+                Some(config) => serde_json::from_value(config.clone())
+                    .map_err(|_| ProtocolError::InvalidField("config"))?,
+                None => return Err(ProtocolError::MissingField("config")),
+            },
+        },
+        "join960" => ClientCommand::Join960(room(object)?),
+        "solo" => ClientCommand::Solo(room(object)?),
+        "load_pgn" => ClientCommand::LoadPgn {
+            room: room(object)?,
+            pgn: string(object, "pgn")?,
+        },
+        "engine_mode" => ClientCommand::EngineMode,
+        "name" => ClientCommand::Name(
+            validate_name(string(object, "name")?, "name")
+                .map_err(|_| ProtocolError::InvalidField("name"))?,
+        ),
+        "chess_step" => ClientCommand::ChessStep {
+            room: optional(object, "room")?,
+            ply: match object.get("ply") {
+                None | Some(Value::Null) => None,
+                Some(Value::Number(ply)) => Some(
+                    ply.as_u64()
+                        .and_then(|ply| usize::try_from(ply).ok())
+                        .ok_or(ProtocolError::InvalidField("ply"))?,
+                ),
+                Some(_) => return Err(ProtocolError::InvalidField("ply")),
+            },
+            step: parse_step(&string(object, "move")?)
+                .map_err(|_| ProtocolError::InvalidField("move"))?,
+        },
+        "check_move" => ClientCommand::CheckMove(
+            parse_step(&string(object, "move")?)
+                .map_err(|_| ProtocolError::InvalidField("move"))?,
+        ),
+        "hint" => ClientCommand::Hint,
+        "premove" => ClientCommand::Premove(
+            normalize_step(&string(object, "move")?)
+                .map_err(|_| ProtocolError::InvalidField("move"))?,
+        ),
+        "leave" => ClientCommand::Leave(optional(object, "room")?),
+        "list" => ClientCommand::ListRooms,
+        "games" => ClientCommand::ListGames,
+        "whoami" => ClientCommand::WhoAmI,
+        "orientation" => ClientCommand::Orientation(
+            parse_color(&string(object, "orientation")?)
+                .ok_or(ProtocolError::InvalidField("orientation"))?,
+        ),
+        "turn" => ClientCommand::Turn,
+        "status" => ClientCommand::Status,
+        "stats" => ClientCommand::Stats,
+        "players" => ClientCommand::Players(optional(object, "room")?),
+        "moves" => ClientCommand::Moves(
+            Square::parse(&string(object, "square")?)
+                .ok_or(ProtocolError::InvalidField("square"))?,
+        ),
+        "history" => ClientCommand::History,
+        "pgn" => ClientCommand::Pgn,
+        "rating" => ClientCommand::Rating(string(object, "name")?),
+        "archive" => ClientCommand::Archive(game_id(object)?),
+        "replay" => ClientCommand::Replay {
+            id: game_id(object)?,
+            speed: match object.get("speed") {
+                Some(Value::Number(speed)) => {
+                    replay_speed(speed.as_u64().and_then(|speed| u32::try_from(speed).ok()))
+                        .ok_or(ProtocolError::InvalidField("speed"))?
+                }
+                Some(_) => return Err(ProtocolError::InvalidField("speed")),
+                None => 1,
+            },
+        },
+        "replay_stop" => ClientCommand::ReplayStop,
+        "resign" | "give_up" => ClientCommand::Resign,
+        "abort" => ClientCommand::Abort,
+        "claim_draw" => ClientCommand::ClaimDraw,
+        "offer_draw" => ClientCommand::OfferDraw,
+        "accept_draw" => ClientCommand::AcceptDraw,
+        "propose_time" => ClientCommand::ProposeTime(match object.get("time_control") {
+            Some(time_control) => serde_json::from_value(time_control.clone())
+                .map_err(|_| ProtocolError::InvalidField("time_control"))?,
+            None => return Err(ProtocolError::MissingField("time_control")),
+        }),
+        "accept_time" => ClientCommand::AcceptTime,
+        "decline_draw" => ClientCommand::DeclineDraw,
+        "draw_status" => ClientCommand::DrawStatus,
+        "offer_takeback" => ClientCommand::OfferTakeback,
+        "accept_takeback" => ClientCommand::AcceptTakeback,
+        "berserk" => ClientCommand::Berserk,
+        "mirror" if DEBUG_COMMANDS => ClientCommand::Mirror,
+        "perft" if DEBUG_COMMANDS => ClientCommand::Perft(
+            perft_depth(
+                object
+                    .get("depth")
+                    .and_then(Value::as_u64)
+                    .and_then(|depth| u32::try_from(depth).ok()),
+            )
+            .ok_or(ProtocolError::InvalidField("depth"))?,
+        ),
+        "restart" | "restart_game" => ClientCommand::Rematch,
+        "quickmatch" => ClientCommand::QuickMatch,
+        "play_bot" => ClientCommand::PlayBot,
+        "promote" => ClientCommand::Promote,
+        "flag" => ClientCommand::Flag,
+        "want_seat" => ClientCommand::WantSeat,
+        "pass_seat" => ClientCommand::PassSeat(match object.get("to") {
+            Some(Value::Number(id)) => id
+                .as_u64()
+                .and_then(|id| usize::try_from(id).ok())
+                .ok_or(ProtocolError::InvalidField("to"))?,
+            Some(_) => return Err(ProtocolError::InvalidField("to")),
+            None => return Err(ProtocolError::MissingField("to")),
+        }),
+        "mute_spectators" => ClientCommand::MuteSpectators(true),
+        "unmute_spectators" => ClientCommand::MuteSpectators(false),
+        "chat" | "say" => ClientCommand::Say {
+            scope: ChatScope::All,
+            text: string(object, "text")?,
+        },
+        "say_players" => ClientCommand::Say {
+            scope: ChatScope::Players,
+            text: string(object, "text")?,
+        },
+        "say_spectators" => ClientCommand::Say {
+            scope: ChatScope::Spectators,
+            text: string(object, "text")?,
+        },
+        _ => return Err(ProtocolError::UnknownType(kind)),
+    };
+    Ok(command)
+}
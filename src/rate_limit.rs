@@ -0,0 +1,73 @@
+//! Ограничение частоты сообщений: корзина жетонов, которая пополняется с постоянной скоростью.
+
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    /// Сколько жетонов помещается в корзину, то есть сколько сообщений можно прислать разом
+    capacity: f64,
+    /// Сколько жетонов добавляется за секунду
+    rate: f64,
+    tokens: f64,
+    /// Когда корзина пополнялась в последний раз
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Полная корзина на `rate` сообщений в секунду
+    pub fn new(rate: u32, now: Instant) -> TokenBucket {
+        TokenBucket {
+            capacity: f64::from(rate),
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            refilled: now,
+        }
+    }
+
+    /// Взять жетон на одно сообщение; `false`, если корзина пуста и сообщение нужно отбросить
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn burst_over_capacity_is_dropped() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(5, start);
+        let accepted = (0..20).filter(|_| bucket.try_take(start)).count();
+        assert_eq!(accepted, 5);
+        assert!(!bucket.try_take(start));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(5, start);
+        while bucket.try_take(start) {}
+
+        // за 0,4 секунды при 5 жетонах в секунду набираются два жетона
+        let later = start + Duration::from_millis(400);
+        let accepted = (0..5).filter(|_| bucket.try_take(later)).count();
+        assert_eq!(accepted, 2);
+
+        // и корзина не переполняется сверх ёмкости
+        let much_later = later + Duration::from_secs(60);
+        let accepted = (0..20).filter(|_| bucket.try_take(much_later)).count();
+        assert_eq!(accepted, 5);
+    }
+}
@@ -0,0 +1,21 @@
+//! Рейтинг Эло: после партии рейтинги игроков сдвигаются на разницу между набранными
+//! и ожидаемыми очками.
+
+/// Рейтинг игрока, который ещё не играл
+pub const INITIAL_RATING: f64 = 1500.0;
+
+/// Насколько сильно одна партия меняет рейтинг
+const K_FACTOR: f64 = 32.0;
+
+/// Ожидаемые очки игрока с рейтингом `rating` против соперника с рейтингом `opponent`
+fn expected_score(rating: f64, opponent: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0))
+}
+
+/// Новые рейтинги белых и чёрных после партии, в которой белые набрали `white_score`:
+/// 1 за победу, 0.5 за ничью, 0 за поражение
+pub fn update(white: f64, black: f64, white_score: f64) -> (f64, f64) {
+    let expected = expected_score(white, black);
+    let change = K_FACTOR * (white_score - expected);
+    (white + change, black - change)
+}
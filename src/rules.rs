@@ -0,0 +1,397 @@
+//! Правила, которые зависят от положения королей: битые поля, шах, мат и пат,
+//! а также ничья из-за недостатка материала и мёртвые позиции.
+
+use crate::board::{
+    Board, Color, Move, MoveError, Piece, PieceKind, Square, BISHOP_DIRECTIONS, KING_OFFSETS,
+    KNIGHT_OFFSETS, ROOK_DIRECTIONS,
+};
+
+/// Поле, на котором стоит король стороны
+fn king_square(board: &Board, side: Color) -> Option<Square> {
+    Square::all().find(|&square| {
+        matches!(board.piece_at(square), Some(piece) if piece.color == side && piece.kind == PieceKind::King)
+    })
+}
+
+/// Бьёт ли сторона `by_side` указанное поле
+pub fn is_square_attacked(board: &Board, square: Square, by_side: Color) -> bool {
+    let is_attacker = |from: Option<Square>, kinds: &[PieceKind]| {
+        from.and_then(|from| board.piece_at(from))
+            .is_some_and(|piece| piece.color == by_side && kinds.contains(&piece.kind))
+    };
+
+    if is_attacked_by_pawn(board, square, by_side) {
+        return true;
+    }
+
+    if KNIGHT_OFFSETS
+        .iter()
+        .any(|&(files, ranks)| is_attacker(square.offset(files, ranks), &[PieceKind::Knight]))
+    {
+        return true;
+    }
+
+    if KING_OFFSETS
+        .iter()
+        .any(|&(files, ranks)| is_attacker(square.offset(files, ranks), &[PieceKind::King]))
+    {
+        return true;
+    }
+
+    let sliders = [
+        (&BISHOP_DIRECTIONS, [PieceKind::Bishop, PieceKind::Queen]),
+        (&ROOK_DIRECTIONS, [PieceKind::Rook, PieceKind::Queen]),
+    ];
+    for (directions, kinds) in sliders {
+        for &(files, ranks) in directions {
+            let mut current = square;
+            while let Some(next) = current.offset(files, ranks) {
+                if board.piece_at(next).is_some() {
+                    if is_attacker(Some(next), &kinds) {
+                        return true;
+                    }
+                    break;
+                }
+                current = next;
+            }
+        }
+    }
+
+    false
+}
+
+/// Бьёт ли указанное поле пешка стороны `by_side`
+fn is_attacked_by_pawn(board: &Board, square: Square, by_side: Color) -> bool {
+    // пешки бьют по диагонали вперёд, поэтому ищем их на шаг назад от поля
+    let pawn = Some(Piece::new(by_side, PieceKind::Pawn));
+    [-1, 1].iter().any(|&files| {
+        square
+            .offset(files, -by_side.pawn_direction())
+            .is_some_and(|from| board.piece_at(from) == pawn)
+    })
+}
+
+/// Находится ли король стороны под шахом
+pub fn is_in_check(board: &Board, side: Color) -> bool {
+    king_square(board, side)
+        .is_some_and(|square| is_square_attacked(board, square, side.opposite()))
+}
+
+/// Не оставляет ли ход собственного короля под шахом
+fn keeps_king_safe(board: &Board, mv: Move, side: Color) -> bool {
+    let mut next = board.clone();
+    next.apply(mv);
+    !is_in_check(&next, side)
+}
+
+/// Проверяет ход стороны, которая сейчас ходит: фигура должна так ходить, а собственный
+/// король не должен после хода оказаться под шахом
+pub fn check_legal(board: &Board, mv: &Move) -> Result<(), MoveError> {
+    board.check_move(mv)?;
+    if keeps_king_safe(board, *mv, board.side_to_move()) {
+        Ok(())
+    } else {
+        Err(MoveError::KingInCheck)
+    }
+}
+
+/// Ходы стороны, после которых её король не остаётся под шахом
+pub fn legal_moves(board: &Board, side: Color) -> Vec<Move> {
+    board
+        .pseudo_legal_moves(side)
+        .into_iter()
+        .filter(|&mv| keeps_king_safe(board, mv, side))
+        .collect()
+}
+
+/// Поля, на которые может пойти фигура с поля `from`. Список пуст, если поле пустое
+/// или на нём фигура стороны, которая сейчас не ходит.
+pub fn legal_moves_from(board: &Board, from: Square) -> Vec<Square> {
+    let side = board.side_to_move();
+    let mut targets: Vec<Square> = legal_moves(board, side)
+        .into_iter()
+        .filter(|mv| mv.from == from)
+        .map(|mv| mv.to)
+        .collect();
+    // превращения в разные фигуры ведут на одно и то же поле
+    targets.dedup();
+    targets
+}
+
+/// Есть ли у стороны хотя бы один допустимый ход
+pub fn has_legal_moves(board: &Board, side: Color) -> bool {
+    board
+        .pseudo_legal_moves(side)
+        .into_iter()
+        .any(|mv| keeps_king_safe(board, mv, side))
+}
+
+/// Стороне поставлен мат: король под шахом и спастись от шаха нечем
+pub fn is_checkmate(board: &Board, side: Color) -> bool {
+    is_in_check(board, side) && !has_legal_moves(board, side)
+}
+
+/// Пат: король не под шахом, но ходить нечем
+pub fn is_stalemate(board: &Board, side: Color) -> bool {
+    !is_in_check(board, side) && !has_legal_moves(board, side)
+}
+
+/// Число позиций, в которые ведут все цепочки допустимых ходов длиной `depth` полуходов.
+/// Для известных позиций эти числа опубликованы, и по ним проверяют генератор ходов.
+pub fn perft(board: &Board, depth: u32) -> u64 {
+    let moves = legal_moves(board, board.side_to_move());
+    if depth <= 1 {
+        return if depth == 0 { 1 } else { moves.len() as u64 };
+    }
+    moves
+        .into_iter()
+        .map(|mv| {
+            let mut next = board.clone();
+            next.apply(mv);
+            perft(&next, depth - 1)
+        })
+        .sum()
+}
+
+/// Недостаточно материала для мата: кроме королей на доске остался один конь или слон,
+/// либо только слоны, и все они на полях одного цвета.
+///
+/// Других мёртвых позиций, которые определяются одним составом фигур, нет: при K+N против
+/// K+N или разнопольных слонах мат возможен, если соперник поможет, поэтому такие партии
+/// продолжаются. Запертые пешки распознаёт `is_dead_position`.
+pub fn is_insufficient_material(board: &Board) -> bool {
+    let mut minors = Vec::new();
+    for square in Square::all() {
+        match board.piece_at(square).map(|piece| piece.kind) {
+            None | Some(PieceKind::King) => {}
+            Some(kind @ (PieceKind::Knight | PieceKind::Bishop)) => minors.push((kind, square)),
+            Some(_) => return false,
+        }
+    }
+
+    match minors.as_slice() {
+        [] | [_] => true,
+        [(_, first), ..] => {
+            let color = (first.file() + first.rank()) % 2;
+            minors.iter().all(|&(kind, square)| {
+                kind == PieceKind::Bishop && (square.file() + square.rank()) % 2 == color
+            })
+        }
+    }
+}
+
+/// Мёртвая позиция, которую не видно по одному составу фигур: кроме королей на доске только
+/// пешки, все они упёрлись в пешки и бить им некого, а ни один король не может подойти
+/// к пешке соперника. Такая позиция уже не изменится, кроме ходов королей, и мата в ней
+/// не будет. Проверка осторожная: если король может хотя бы встать рядом с чужой пешкой,
+/// позиция считается живой, даже если пешка защищена.
+pub fn is_dead_position(board: &Board) -> bool {
+    let mut pawns = Vec::new();
+    for square in Square::all() {
+        match board.piece_at(square) {
+            None => {}
+            Some(piece) if piece.kind == PieceKind::King => {}
+            Some(piece) if piece.kind == PieceKind::Pawn => pawns.push((square, piece.color)),
+            Some(_) => return false,
+        }
+    }
+    if pawns.is_empty() {
+        return false;
+    }
+
+    // пешка стоит, пока перед ней пешка; бить ей некого, потому что короля пешка не бьёт,
+    // а на битое пешкой поле король не встанет
+    let blocked = pawns.iter().all(|&(square, color)| {
+        square
+            .offset(0, color.pawn_direction())
+            .and_then(|ahead| board.piece_at(ahead))
+            .is_some_and(|piece| piece.kind == PieceKind::Pawn)
+    });
+    let frozen = [Color::White, Color::Black].into_iter().all(|color| {
+        !is_in_check(board, color)
+            && board
+                .pseudo_legal_moves(color)
+                .iter()
+                .all(|mv| pawns.iter().all(|&(square, _)| square != mv.from))
+    });
+    if !blocked || !frozen {
+        return false;
+    }
+
+    [Color::White, Color::Black].into_iter().all(|color| {
+        king_square(board, color).is_some_and(|king| !king_reaches_pawn(board, king, color))
+    })
+}
+
+/// Может ли король стороны `side` с поля `king` встать рядом с пешкой соперника, если
+/// пешки не двигаются. Чужой король не мешает, так что ответ «да» бывает и лишним.
+fn king_reaches_pawn(board: &Board, king: Square, side: Color) -> bool {
+    let opponent = side.opposite();
+    let enemy_pawn = Some(Piece::new(opponent, PieceKind::Pawn));
+    let mut visited = [false; 64];
+    visited[king.index()] = true;
+    let mut frontier = vec![king];
+    while let Some(square) = frontier.pop() {
+        let neighbours = KING_OFFSETS
+            .iter()
+            .filter_map(|&(files, ranks)| square.offset(files, ranks));
+        for next in neighbours {
+            match board.piece_at(next) {
+                piece if piece == enemy_pawn => return true,
+                Some(piece) if piece.kind == PieceKind::Pawn => continue,
+                _ => {}
+            }
+            if !visited[next.index()] && !is_attacked_by_pawn(board, next, opponent) {
+                visited[next.index()] = true;
+                frontier.push(next);
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn board(fen: &str) -> Board {
+        Board::from_fen(fen).unwrap()
+    }
+
+    #[test]
+    fn back_rank_mate() {
+        let mut position = board("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1");
+        assert!(!is_checkmate(&position, Color::Black));
+        position.apply(Move::parse("a1a8").unwrap());
+        assert!(is_checkmate(&position, Color::Black));
+    }
+
+    #[test]
+    fn back_rank_check_with_luft_is_not_mate() {
+        let position = board("R5k1/5pp1/8/8/8/8/8/6K1 b - - 0 1");
+        assert!(is_in_check(&position, Color::Black));
+        assert!(!is_checkmate(&position, Color::Black));
+    }
+
+    #[test]
+    fn smothered_mate() {
+        let position = board("6rk/5Npp/8/8/8/8/8/6K1 b - - 0 1");
+        assert!(is_checkmate(&position, Color::Black));
+        assert!(!is_stalemate(&position, Color::Black));
+    }
+
+    #[test]
+    fn locked_pawns_with_walled_kings_are_dead() {
+        let position = board("8/8/1k6/p1p1p1p1/P1P1P1P1/8/3K4/8 w - - 0 1");
+        assert!(!is_insufficient_material(&position));
+        assert!(is_dead_position(&position));
+    }
+
+    #[test]
+    fn king_that_can_reach_a_pawn_keeps_the_position_alive() {
+        // по g- и h-вертикали белый король проходит к пешке e5
+        assert!(!is_dead_position(&board(
+            "8/8/1k6/p1p1p3/P1P1P3/8/3K4/8 w - - 0 1"
+        )));
+        assert!(!is_dead_position(&board(
+            "8/8/4k3/4p3/4P3/4K3/8/8 w - - 0 1"
+        )));
+    }
+
+    #[test]
+    fn minor_pieces_are_not_dead_positions() {
+        // K+N против K+N: мат возможен, если соперник поможет
+        assert!(!is_dead_position(&board(
+            "4k3/8/2n5/8/8/5N2/8/4K3 w - - 0 1"
+        )));
+        assert!(!is_insufficient_material(&board(
+            "4k3/8/2n5/8/8/5N2/8/4K3 w - - 0 1"
+        )));
+    }
+
+    #[test]
+    fn queen_stalemates_cornered_king() {
+        let position = board("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1");
+        assert!(is_stalemate(&position, Color::Black));
+        assert!(!is_checkmate(&position, Color::Black));
+        assert!(legal_moves(&position, Color::Black).is_empty());
+    }
+
+    #[test]
+    fn cornered_king_with_a_move_is_not_stalemate() {
+        let position = board("7k/8/6K1/8/8/8/8/5Q2 b - - 0 1");
+        assert!(!is_in_check(&position, Color::Black));
+        assert!(!is_stalemate(&position, Color::Black));
+    }
+
+    fn check(position: &Board, mv: &str) -> Result<(), MoveError> {
+        check_legal(position, &Move::parse(mv).unwrap())
+    }
+
+    #[test]
+    fn pinned_piece_cannot_leave_the_line() {
+        let position = board("4k3/4r3/8/8/8/8/4N3/4K3 w - - 0 1");
+        assert_eq!(check(&position, "e2c3"), Err(MoveError::KingInCheck));
+        assert_eq!(check(&position, "e2g1"), Err(MoveError::KingInCheck));
+        assert_eq!(check(&position, "e1d1"), Ok(()));
+    }
+
+    #[test]
+    fn king_cannot_walk_into_rook_line() {
+        let position = board("3rk3/8/8/8/8/8/8/4K3 w - - 0 1");
+        assert_eq!(check(&position, "e1d1"), Err(MoveError::KingInCheck));
+        assert_eq!(check(&position, "e1d2"), Err(MoveError::KingInCheck));
+        assert_eq!(check(&position, "e1f1"), Ok(()));
+        // ход, который фигура так сделать не может, отклоняется раньше проверки шаха
+        assert_eq!(check(&position, "e1e3"), Err(MoveError::Illegal));
+    }
+
+    /// Опубликованные значения perft для позиций, на которых обычно ловят ошибки генератора
+    /// ходов: рокировки, взятия на проходе, превращения и связки.
+    fn assert_perft(fen: &str, expected: &[u64]) {
+        let position = board(fen);
+        for (depth, &nodes) in (1..).zip(expected) {
+            assert_eq!(perft(&position, depth), nodes, "{fen}, depth {depth}");
+        }
+    }
+
+    #[test]
+    fn perft_start_position() {
+        assert_perft(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            &[20, 400, 8902, 197281],
+        );
+    }
+
+    #[test]
+    fn perft_kiwipete() {
+        assert_perft(
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            &[48, 2039, 97862],
+        );
+    }
+
+    #[test]
+    fn perft_position_3() {
+        assert_perft(
+            "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+            &[14, 191, 2812, 43238],
+        );
+    }
+
+    #[test]
+    fn perft_position_4() {
+        assert_perft(
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            &[6, 264, 9467],
+        );
+    }
+
+    #[test]
+    fn perft_position_5() {
+        assert_perft(
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            &[44, 1486, 62379],
+        );
+    }
+}
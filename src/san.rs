@@ -0,0 +1,282 @@
+//! Запись и разбор ходов в стандартной алгебраической нотации (SAN): `Nf3`, `exd5`, `O-O`,
+//! `e8=Q`, `Qh5+`, `Qh7#`.
+
+use std::fmt;
+
+use crate::{
+    board::{Board, Color, Move, Piece, PieceKind, Square},
+    rules,
+};
+
+/// Почему ход в SAN не удалось превратить в ход на доске
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SanError {
+    /// Запись не похожа на ход в SAN
+    Malformed(String),
+    /// Ни один допустимый ход не подходит под запись
+    Illegal(String),
+    /// Под запись подходит несколько допустимых ходов
+    Ambiguous(String),
+}
+
+impl SanError {
+    /// Код ошибки для клиента
+    pub fn code(&self) -> &'static str {
+        match self {
+            SanError::Malformed(_) => "malformed_move",
+            SanError::Illegal(_) => "illegal_move",
+            SanError::Ambiguous(_) => "ambiguous_move",
+        }
+    }
+}
+
+impl fmt::Display for SanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SanError::Malformed(san) => write!(f, "malformed move {:?}", san),
+            SanError::Illegal(san) => write!(f, "illegal move {:?}", san),
+            SanError::Ambiguous(san) => write!(f, "ambiguous move {:?}", san),
+        }
+    }
+}
+
+/// Записать ход в SAN. Ход должен быть допустим в позиции `board`, то есть ещё не сделан.
+/// Шах отмечается `+`, мат — `#`.
+pub fn to_san(board: &Board, mv: &Move) -> String {
+    let piece = match board.piece_at(mv.from) {
+        Some(piece) => piece,
+        None => return mv.to_string(),
+    };
+
+    let mut san = move_text(board, mv, piece);
+    let mut next = board.clone();
+    next.apply(*mv);
+    let opponent = piece.color.opposite();
+    if rules::is_checkmate(&next, opponent) {
+        san.push('#');
+    } else if rules::is_in_check(&next, opponent) {
+        san.push('+');
+    }
+    san
+}
+
+/// Найти ход, записанный в SAN, среди допустимых ходов позиции `board`. Отметки шаха
+/// и оценки хода, например `+`, `#` или `!?`, не проверяются; рокировку можно записать
+/// и нулями, `0-0`.
+pub fn parse_san(board: &Board, san: &str) -> Result<Move, SanError> {
+    let text = strip_annotations(san);
+    let legal = rules::legal_moves(board, board.side_to_move());
+
+    let candidates: Vec<Move> = match castling_side(text) {
+        Some(kingside) => legal
+            .into_iter()
+            .filter(|mv| {
+                board
+                    .castling_rook(mv)
+                    .is_some_and(|(rook_from, _)| (rook_from.file() > mv.from.file()) == kingside)
+            })
+            .collect(),
+        None => {
+            let pattern =
+                SanPattern::parse(text).ok_or_else(|| SanError::Malformed(san.to_owned()))?;
+            legal
+                .into_iter()
+                .filter(|mv| pattern.matches(board, mv))
+                .collect()
+        }
+    };
+
+    match candidates.as_slice() {
+        [mv] => Ok(*mv),
+        [] => Err(SanError::Illegal(san.to_owned())),
+        _ => Err(SanError::Ambiguous(san.to_owned())),
+    }
+}
+
+/// Похожа ли запись на ход в SAN. Есть ли такой ход, зависит от позиции.
+pub fn is_san(san: &str) -> bool {
+    let text = strip_annotations(san);
+    castling_side(text).is_some() || SanPattern::parse(text).is_some()
+}
+
+/// Запись хода без отметок шаха и оценок
+fn strip_annotations(san: &str) -> &str {
+    san.trim().trim_end_matches(['+', '#', '!', '?'])
+}
+
+/// Рокировка в короткую (`true`) или длинную сторону
+fn castling_side(text: &str) -> Option<bool> {
+    match text {
+        "O-O" | "0-0" => Some(true),
+        "O-O-O" | "0-0-0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Ход в SAN, кроме рокировки: какая фигура, откуда, если это уточнено, и куда идёт
+struct SanPattern {
+    kind: PieceKind,
+    from_file: Option<u8>,
+    from_rank: Option<u8>,
+    to: Square,
+    promotion: Option<PieceKind>,
+}
+
+impl SanPattern {
+    fn parse(text: &str) -> Option<SanPattern> {
+        let mut rest = text;
+        let kind = match rest.chars().next()? {
+            'K' => PieceKind::King,
+            'Q' => PieceKind::Queen,
+            'R' => PieceKind::Rook,
+            'B' => PieceKind::Bishop,
+            'N' => PieceKind::Knight,
+            _ => PieceKind::Pawn,
+        };
+        if kind != PieceKind::Pawn {
+            rest = &rest[1..];
+        }
+
+        // превращение записывается `e8=Q`, но встречается и `e8Q`
+        let mut promotion = None;
+        if kind == PieceKind::Pawn {
+            if let Some(letter) = rest.chars().last().filter(char::is_ascii_uppercase) {
+                promotion = Some(PieceKind::from_promotion_char(letter.to_ascii_lowercase())?);
+                rest = rest[..rest.len() - 1].trim_end_matches('=');
+            }
+        }
+
+        let split = rest.len().checked_sub(2)?;
+        let to = Square::parse(rest.get(split..)?)?;
+        // знак взятия не проверяется: взятие видно по доске
+        let mut from_file = None;
+        let mut from_rank = None;
+        for c in rest[..split].trim_end_matches(['x', ':']).chars() {
+            match c {
+                'a'..='h' if from_file.is_none() && from_rank.is_none() => {
+                    from_file = Some(c as u8 - b'a')
+                }
+                '1'..='8' if from_rank.is_none() => from_rank = Some(c as u8 - b'1'),
+                _ => return None,
+            }
+        }
+        // пешка без взятия ходит по своей вертикали
+        if kind == PieceKind::Pawn && from_file.is_none() {
+            from_file = Some(to.file());
+        }
+
+        Some(SanPattern {
+            kind,
+            from_file,
+            from_rank,
+            to,
+            promotion,
+        })
+    }
+
+    fn matches(&self, board: &Board, mv: &Move) -> bool {
+        mv.to == self.to
+            && mv.promotion == self.promotion
+            && board.piece_at(mv.from).map(|piece| piece.kind) == Some(self.kind)
+            && self.from_file.is_none_or(|file| mv.from.file() == file)
+            && self.from_rank.is_none_or(|rank| mv.from.rank() == rank)
+            // король, который идёт на поле рокировки, записывается как `O-O`
+            && board.castling_rook(mv).is_none()
+    }
+}
+
+/// Ход в SAN без отметки шаха
+fn move_text(board: &Board, mv: &Move, piece: Piece) -> String {
+    if let Some((rook_from, _)) = board.castling_rook(mv) {
+        return if rook_from.file() > mv.from.file() {
+            "O-O"
+        } else {
+            "O-O-O"
+        }
+        .to_owned();
+    }
+
+    let capture = board.piece_at(mv.to).is_some()
+        || (piece.kind == PieceKind::Pawn && mv.from.file() != mv.to.file());
+
+    let mut san = String::new();
+    if piece.kind == PieceKind::Pawn {
+        if capture {
+            san.push((b'a' + mv.from.file()) as char);
+        }
+    } else {
+        san.push(piece_letter(piece));
+        san.push_str(&disambiguation(board, mv, piece));
+    }
+
+    if capture {
+        san.push('x');
+    }
+    san.push_str(&mv.to.to_string());
+    if let Some(kind) = mv.promotion {
+        san.push('=');
+        san.push(piece_letter(Piece::new(piece.color, kind)));
+    }
+    san
+}
+
+/// Заглавная буква фигуры в SAN
+fn piece_letter(piece: Piece) -> char {
+    Piece::new(Color::White, piece.kind).to_char()
+}
+
+/// Уточнение вертикали или горизонтали, если на то же поле может пойти другая
+/// такая же фигура
+fn disambiguation(board: &Board, mv: &Move, piece: Piece) -> String {
+    let rivals: Vec<Move> = rules::legal_moves(board, piece.color)
+        .into_iter()
+        .filter(|other| {
+            other.to == mv.to && other.from != mv.from && board.piece_at(other.from) == Some(piece)
+        })
+        .collect();
+
+    if rivals.is_empty() {
+        String::new()
+    } else if rivals
+        .iter()
+        .all(|other| other.from.file() != mv.from.file())
+    {
+        ((b'a' + mv.from.file()) as char).to_string()
+    } else if rivals
+        .iter()
+        .all(|other| other.from.rank() != mv.from.rank())
+    {
+        (mv.from.rank() + 1).to_string()
+    } else {
+        mv.from.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn san_after(moves: &[&str], mv: &str) -> String {
+        let mut board = Board::new();
+        for mv in moves {
+            board.apply(Move::parse(mv).unwrap());
+        }
+        to_san(&board, &Move::parse(mv).unwrap())
+    }
+
+    #[test]
+    fn quiet_move_has_no_mark() {
+        assert_eq!(san_after(&["e2e4", "e7e5"], "d1h5"), "Qh5");
+    }
+
+    #[test]
+    fn check_is_marked_with_plus() {
+        assert_eq!(san_after(&["e2e4", "f7f5"], "d1h5"), "Qh5+");
+    }
+
+    #[test]
+    fn checkmate_is_marked_with_hash() {
+        let board = Board::from_fen("5rk1/5pp1/8/8/8/3B4/8/6KQ w - - 0 1").unwrap();
+        assert_eq!(to_san(&board, &Move::parse("h1h7").unwrap()), "Qh7#");
+    }
+}
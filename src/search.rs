@@ -0,0 +1,104 @@
+//! Подсказка хода: оценка позиции по материалу и положению фигур и неглубокий перебор
+//! вариантов с альфа-бета отсечением.
+
+use crate::{
+    board::{Board, Color, Move, PieceKind, Square},
+    rules,
+};
+
+/// Оценка мата; мат ближе к корню оценивается выше
+const MATE: i32 = 100_000;
+
+/// Стоимость фигуры в сотых долях пешки
+fn piece_value(kind: PieceKind) -> i32 {
+    match kind {
+        PieceKind::Pawn => 100,
+        PieceKind::Knight => 320,
+        PieceKind::Bishop => 330,
+        PieceKind::Rook => 500,
+        PieceKind::Queen => 900,
+        PieceKind::King => 0,
+    }
+}
+
+/// Надбавка за положение фигуры: конь, слон и ферзь сильнее в центре, пешка — чем ближе
+/// к превращению и к центральным вертикалям
+fn square_bonus(kind: PieceKind, color: Color, square: Square) -> i32 {
+    // расстояние до центра: 1 на четырёх центральных полях, 7 в углу
+    let file = (2 * i32::from(square.file()) - 7).abs();
+    let rank = (2 * i32::from(square.rank()) - 7).abs();
+    let centre = 7 - file.max(rank);
+    match kind {
+        PieceKind::Knight | PieceKind::Bishop => 5 * centre,
+        PieceKind::Queen => 2 * centre,
+        PieceKind::Pawn => {
+            let advance = match color {
+                Color::White => i32::from(square.rank()) - 1,
+                Color::Black => 6 - i32::from(square.rank()),
+            };
+            5 * advance + 2 * (7 - file)
+        }
+        PieceKind::Rook | PieceKind::King => 0,
+    }
+}
+
+/// Оценка позиции с точки зрения стороны, которая ходит
+pub fn evaluate(board: &Board) -> i32 {
+    let side = board.side_to_move();
+    Square::all()
+        .filter_map(|square| Some((square, board.piece_at(square)?)))
+        .map(|(square, piece)| {
+            let score = piece_value(piece.kind) + square_bonus(piece.kind, piece.color, square);
+            if piece.color == side {
+                score
+            } else {
+                -score
+            }
+        })
+        .sum()
+}
+
+/// Лучший ход стороны, которая ходит, при переборе на `depth` полуходов; `None`, если
+/// ходить нечем. Из равных по оценке ходов выбирается первый.
+pub fn best_move(board: &Board, depth: u32) -> Option<Move> {
+    let mut best = None;
+    let mut alpha = -MATE - 1;
+    for mv in rules::legal_moves(board, board.side_to_move()) {
+        let mut next = board.clone();
+        next.apply(mv);
+        let score = -negamax(&next, depth.saturating_sub(1), 1, -MATE - 1, -alpha);
+        if best.is_none() || score > alpha {
+            alpha = score;
+            best = Some(mv);
+        }
+    }
+    best
+}
+
+/// Оценка позиции перебором на `depth` полуходов; `ply` — сколько полуходов сделано
+/// от корня
+fn negamax(board: &Board, depth: u32, ply: i32, mut alpha: i32, beta: i32) -> i32 {
+    let side = board.side_to_move();
+    let moves = rules::legal_moves(board, side);
+    if moves.is_empty() {
+        return if rules::is_in_check(board, side) {
+            -MATE + ply
+        } else {
+            0
+        };
+    }
+    if depth == 0 {
+        return evaluate(board);
+    }
+
+    for mv in moves {
+        let mut next = board.clone();
+        next.apply(mv);
+        let score = -negamax(&next, depth - 1, ply + 1, -beta, -alpha);
+        if score >= beta {
+            return score;
+        }
+        alpha = alpha.max(score);
+    }
+    alpha
+}
@@ -2,28 +2,124 @@
 //! И управляет доступными комнатами. Пиры отправляют сообщения другим пирам в той же комнате через `ChatServer`.
 
 use std::{
-    collections::{HashMap, HashSet},
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
-use actix::prelude::*;
-use rand::{self, rngs::ThreadRng, Rng};
+use actix::{prelude::*, WeakAddr};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use uuid::Uuid;
 
-/// Сервер чата отправляет эти сообщения в сессию
-#[derive(Message)]
+use crate::{
+    archive::{Archive, ArchivedGame, ARCHIVE_CAPACITY},
+    board::{Board, Color, Move, Square, CHESS960_POSITIONS},
+    bot::{self, BOT_ID, BOT_NAME},
+    clock::ClockConfig,
+    command::ChatScope,
+    event::{GameSummary, ServerEvent, SquareMove},
+    game::{Game, GameConfig, GameId, Outcome, SeatClock, Variant},
+    pgn, rating, rules, san, search,
+};
+
+/// Как часто сервер проверяет часы партий и рассылает оставшееся время
+const CLOCK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Сколько место отключившегося игрока ждёт его возвращения, прежде чем ему
+/// засчитается поражение по времени
+const RECONNECT_GRACE: Duration = Duration::from_secs(30);
+
+/// Как часто сервер рассылает число посетителей, если оно изменилось, и сообщает лобби,
+/// кто в него вошёл и кто вышел
+const VISITORS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Игровая комната, в которой так долго не было ни ходов, ни сообщений, закрывается
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Как часто сервер ищет заброшенные игровые комнаты
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Наибольшая длина сообщения чата в символах; длинные сообщения обрезаются
+const MAX_CHAT_LENGTH: usize = 500;
+
+/// Ответ на ход соперника быстрее этого подозрителен, если так отвечают раз за разом
+const FAST_REPLY: Duration = Duration::from_millis(200);
+
+/// После скольких быстрых ответов подряд, совпавших с лучшим ходом подсказки, игрок
+/// в рейтинговой партии попадает в журнал
+const SUSPICIOUS_STREAK: usize = 10;
+
+/// Сколько полуходов без взятий и ходов пешками дают право на ничью
+const FIFTY_MOVE_PLIES: u32 = 100;
+
+/// На сколько полуходов вперёд перебираются ходы для подсказки
+const HINT_DEPTH: u32 = 2;
+
+/// Сколько клиенты ждут после предупреждения об остановке сервера, прежде чем
+/// сервер чата остановится и соединения будут закрыты
+pub const SHUTDOWN_NOTICE: Duration = Duration::from_secs(3);
+
+/// Сервер чата отправляет эти сообщения в сессию: `ServerEvent`, сериализованное в JSON
+#[derive(Clone, Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message {
+    pub text: String,
+    /// Сообщение чата: медленный клиент может его не получить
+    pub chat: bool,
+    /// Ход соперника в записи UCI для сессий в режиме движка
+    pub uci: Option<String>,
+    /// Сколько сообщений чата перед этим не поместилось в почтовый ящик сессии
+    pub dropped: usize,
+}
 
-/// Сообщение для связи с сервером чата
+impl From<&ServerEvent> for Message {
+    fn from(event: &ServerEvent) -> Message {
+        Message {
+            text: event.to_json(),
+            chat: event.is_chat(),
+            uci: event.uci().map(str::to_owned),
+            dropped: 0,
+        }
+    }
+}
 
-/// Создается новый сеанс чата
-#[derive(Message)]
-#[rtype(usize)]
+/// Сообщение для связи с сервером чата
+///
+/// Создается новый сеанс чата или продолжается прежний, если клиент вернулся с токеном
+/// вовремя
 pub struct Connect {
     pub addr: Recipient<Message>,
+    /// Куда сообщить, что сессии уступили место за доской
+    pub seated: Recipient<Seated>,
+    /// Токен прежней сессии
+    pub reconnect: Option<Uuid>,
+}
+
+impl actix::Message for Connect {
+    type Result = Connected;
+}
+
+/// Сессия, к которой подключился клиент
+pub struct Connected {
+    pub id: usize,
+    /// Текущая комната сессии
+    pub room: String,
+    /// Все комнаты, в которых сессия находится
+    pub rooms: HashSet<String>,
+    /// Комната, в которую сервер помещает новые сессии
+    pub lobby: String,
+    /// Имя, которое сессия выбрала до переподключения
+    pub name: Option<String>,
+    /// Ориентация доски, которую сессия выбрала до переподключения
+    pub orientation: Option<Color>,
 }
 
 /// Сессия отключена
@@ -31,6 +127,8 @@ pub struct Connect {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: usize,
+    /// Клиент сам закрыл соединение, а не потерял связь
+    pub clean: bool,
 }
 
 /// Отправить сообщение в определенную комнату
@@ -43,6 +141,8 @@ pub struct ClientMessage {
     pub msg: String,
     /// Название номера
     pub room: String,
+    /// Кому в комнате предназначено сообщение
+    pub scope: ChatScope,
 }
 
 /// Список доступных номеров
@@ -52,184 +152,3319 @@ impl actix::Message for ListRooms {
     type Result = Vec<String>;
 }
 
+/// Список партий всех игровых комнат
+pub struct ListGames;
+
+impl actix::Message for ListGames {
+    type Result = Vec<GameSummary>;
+}
+
 /// Присоединитесь к комнате, если комната не существует, создайте новую.
-#[derive(Message)]
-#[rtype(result = "()")]
+/// При отказе возвращается текст ошибки, и сессия в комнату не входит.
+#[derive(Message, Default)]
+#[rtype(result = "Result<(), ServerEvent>")]
 pub struct Join {
     /// Client ID
     pub id: usize,
 
     /// Room name
     pub name: String,
+
+    /// Пароль комнаты: при создании комнаты он её закрывает, при входе проверяется
+    pub password: Option<String>,
+
+    /// Комнату нужно создать; если она уже есть, сессия в неё не входит
+    pub create: bool,
+
+    /// Позиция, с которой начнётся партия в новой комнате
+    pub board: Option<Board>,
+
+    /// Настройки партии в новой комнате; по умолчанию берутся настройки сервера
+    pub config: Option<GameConfig>,
+
+    /// Ходы, которые сразу делаются в партии новой комнаты; они должны быть допустимы
+    pub moves: Vec<Move>,
+
+    /// Войти только зрителем, не занимая места за доской
+    pub spectate: bool,
+
+    /// Играть за обе стороны: сессия занимает оба места за доской
+    pub solo: bool,
+}
+
+/// Сессия покидает комнату и освобождает место в её партии
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    pub id: usize,
+    pub room: String,
+}
+
+/// Сессия отключает или снова включает сообщения зрителей
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MuteSpectators {
+    pub id: usize,
+    pub muted: bool,
+}
+
+/// Сервер останавливается: сессии получают предупреждение, незаконченные партии
+/// прерываются, а через `SHUTDOWN_NOTICE` сервер чата останавливается. Архив хранится
+/// только в памяти и вместе с процессом пропадает.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown;
+
+/// Сессия назвалась
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetName {
+    pub id: usize,
+    pub name: String,
+}
+
+/// Сессия выбрала, какой стороной к себе показывать доску
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetOrientation {
+    pub id: usize,
+    pub orientation: Color,
 }
 
+/// Ход в шахматной партии комнаты
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct ChessGame {
     pub id: usize,
+    /// Ход в координатной нотации, например `e2e4`
     pub step: String,
     pub room: String,
+    /// Номер полухода, под которым ход должен войти в партию; ход с другим номером устарел
+    pub ply: Option<usize>,
+    /// Ход заранее сделан через `Premove` и делается сразу после хода соперника
+    pub premove: bool,
 }
 
-impl Handler<ChessGame> for ChatServer {
-    type Result = ();
+/// Игрок заранее делает ход, пока очередь у соперника
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Premove {
+    pub id: usize,
+    /// Ход в координатной нотации, например `e2e4`
+    pub step: String,
+    pub room: String,
+}
 
-    fn handle(&mut self, msg: ChessGame, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.step.as_str(), msg.id);
-    }
+/// Игрок сдаётся в партии комнаты
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Resign {
+    pub id: usize,
+    pub room: String,
 }
 
+/// Игрок прерывает партию, в которой ещё не было ходов
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Abort {
+    pub id: usize,
+    pub room: String,
+}
 
-/// `ChatServer` управляет чатами и отвечает за координацию чат-сессий.
-///
-/// Implementation is very naïve.
-#[derive(Debug)]
-pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<String, HashSet<usize>>,
-    rng: ThreadRng,
-    visitor_count: Arc<AtomicUsize>,
+/// Игрок предлагает сопернику ничью
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OfferDraw {
+    pub id: usize,
+    pub room: String,
 }
 
-impl ChatServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> ChatServer {
-        // комната по умолчанию
-        let mut rooms = HashMap::new();
-        rooms.insert("Main".to_owned(), HashSet::new());
+/// Игрок соглашается на ничью, предложенную соперником
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AcceptDraw {
+    pub id: usize,
+    pub room: String,
+}
 
-        ChatServer {
-            sessions: HashMap::new(),
-            rooms,
-            rng: rand::thread_rng(),
-            visitor_count,
-        }
-    }
+/// Игрок до первого хода предлагает сопернику другой контроль времени
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ProposeTime {
+    pub id: usize,
+    pub room: String,
+    /// `None` — партия без часов
+    pub time_control: Option<ClockConfig>,
 }
 
-impl ChatServer {
-    /// Отправить сообщение всем пользователям в комнате
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        let _ = addr.do_send(Message(message.to_owned()));
-                    }
-                }
-            }
-        }
-    }
+/// Игрок соглашается на контроль времени, предложенный соперником
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AcceptTime {
+    pub id: usize,
+    pub room: String,
 }
 
-/// Сделать актера из `ChatServer`
-impl Actor for ChatServer {
-    /// Мы собираемся использовать простой Context, нам просто нужна способность общаться с другими актерами.
-    type Context = Context<Self>;
+/// Игрок отклоняет ничью, предложенную соперником
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeclineDraw {
+    pub id: usize,
+    pub room: String,
 }
 
-/// Обработчик для сообщения Connect.
-///
-/// Зарегистрируйте новую сессию и присвойте ей уникальный идентификатор
-impl Handler<Connect> for ChatServer {
-    type Result = usize;
+/// Игрок требует ничью по правилу пятидесяти ходов или троекратного повторения
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClaimDraw {
+    pub id: usize,
+    pub room: String,
+}
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+/// Игрок предлагает вернуть последний ход
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct OfferTakeback {
+    pub id: usize,
+    pub room: String,
+}
 
-        // уведомлять всех пользователей в одной комнате
-        self.send_message("Main", "Someone joined", 0);
+/// Игрок до своего первого хода отдаёт половину времени за очко сверху при победе
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Berserk {
+    pub id: usize,
+    pub room: String,
+}
 
-        // зарегистрировать сессию со случайным идентификатором
-        let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+/// Игрок соглашается вернуть последний ход, как предложил соперник
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AcceptTakeback {
+    pub id: usize,
+    pub room: String,
+}
 
-        // автоматическое присоединение сеанса к главной комнате
-        self.rooms
-            .entry("Main".to_owned())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+/// Отладочная команда: отразить позицию партии комнаты
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Mirror {
+    pub id: usize,
+    pub room: String,
+}
 
-        let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
-        self.send_message("Main", &format!("Total visitors {}", count), 0);
+/// Игрок закончившейся партии хочет сыграть ещё одну
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Rematch {
+    pub id: usize,
+    pub room: String,
+}
 
-        // отправить идентификатор обратно
-        id
-    }
+/// Зритель просит место за доской
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Promote {
+    pub id: usize,
+    pub room: String,
 }
 
-/// Обработчик сообщения об отключении.
-impl Handler<Disconnect> for ChatServer {
-    type Result = ();
+/// Игрок требует победы, потому что время соперника уже вышло
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Flag {
+    pub id: usize,
+    pub room: String,
+}
+
+/// Зритель хочет занять место за доской, когда оно освободится
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WantSeat {
+    pub id: usize,
+    pub room: String,
+}
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+/// Игрок уступает своё место и оставшееся время зрителю той же комнаты, а сам становится
+/// зрителем
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PassSeat {
+    pub id: usize,
+    pub room: String,
+    /// Зритель, который займёт место
+    pub to: usize,
+}
 
-        let mut rooms: Vec<String> = Vec::new();
+/// Игрок уступил сессии своё место за доской в комнате
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Seated {
+    pub room: String,
+}
 
-        // удалить адрес
-        if self.sessions.remove(&msg.id).is_some() {
-            // удалить сессию из всех помещений
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
-                }
-            }
-        }
-        // отправлять сообщения другим пользователям
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
-        }
-    }
+/// Узнать игроков партии комнаты и число зрителей. `None`, если партия в комнате не начата.
+pub struct PlayerList {
+    pub room: String,
 }
 
-/// Обработчик для сообщения Message.
-impl Handler<ClientMessage> for ChatServer {
-    type Result = ();
+impl actix::Message for PlayerList {
+    type Result = Option<ServerEvent>;
+}
 
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
-    }
+/// Найти законченную партию в архиве. `None`, если такой партии нет.
+pub struct GetArchived {
+    pub id: GameId,
 }
 
-/// Обработчик для сообщения `ListRooms`.
-impl Handler<ListRooms> for ChatServer {
-    type Result = MessageResult<ListRooms>;
+impl actix::Message for GetArchived {
+    type Result = Option<ServerEvent>;
+}
 
-    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
-        let mut rooms = Vec::new();
+/// Получить события, которыми партия из архива показывается заново: начальная позиция
+/// и позиция после каждого хода. `None`, если такой партии нет.
+pub struct GetReplay {
+    pub id: GameId,
+}
 
-        for key in self.rooms.keys() {
-            rooms.push(key.to_owned())
-        }
+impl actix::Message for GetReplay {
+    type Result = Option<Vec<ServerEvent>>;
+}
 
-        MessageResult(rooms)
-    }
+/// Узнать рейтинг игрока по имени
+pub struct GetRating {
+    pub name: String,
 }
 
-/// Присоединиться к комнате, отправить сообщение о разъединении в старую комнату, отправить сообщение о присоединении в новую комнату
-impl Handler<Join> for ChatServer {
-    type Result = ();
+impl actix::Message for GetRating {
+    type Result = f64;
+}
 
-    fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
-        let Join { id, name } = msg;
-        let mut rooms = Vec::new();
+/// Узнать, кто такая сессия: её номер, имя и место в партии комнаты
+pub struct WhoAmI {
+    pub id: usize,
+    pub room: String,
+}
 
-        // удалить сессию из всех помещений
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
-            }
-        }
-        // отправлять сообщения другим пользователям
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
-        }
+impl actix::Message for WhoAmI {
+    type Result = ServerEvent;
+}
+
+/// Встать в очередь на быструю партию
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct QuickMatch {
+    pub id: usize,
+    /// Куда сообщить о найденном сопернике
+    pub addr: Recipient<Matched>,
+}
 
-        self.rooms
-            .entry(name.clone())
-            .or_insert_with(HashSet::new)
-            .insert(id);
+/// Сервер нашёл сессии соперника и перевёл её в комнату новой партии
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Matched {
+    pub room: String,
+    pub color: Color,
+}
 
-        self.send_message(&name, "Someone connected", id);
-    }
-}
\ No newline at end of file
+/// Начать партию против бота
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PlayBot {
+    pub id: usize,
+    /// Куда сообщить о комнате новой партии
+    pub addr: Recipient<Matched>,
+}
+
+/// Узнать, какая сторона предложила ничью в партии комнаты. `None`, если предложения нет
+/// или в комнате нет партии.
+pub struct GetDrawOffer {
+    pub room: String,
+}
+
+impl actix::Message for GetDrawOffer {
+    type Result = Option<Color>;
+}
+
+/// Узнать краткое состояние партии комнаты. `None`, если партия в комнате не начата.
+pub struct GetStatus {
+    pub room: String,
+}
+
+impl actix::Message for GetStatus {
+    type Result = Option<ServerEvent>;
+}
+
+/// Проверить, допустим ли ход в партии комнаты, не делая его. Ответ — `MoveCheck` или
+/// ошибка, если в комнате нет партии.
+pub struct CheckMove {
+    pub room: String,
+    /// Ход в координатной нотации или в SAN
+    pub step: String,
+}
+
+impl actix::Message for CheckMove {
+    type Result = ServerEvent;
+}
+
+/// Отладочный подсчёт позиций на `depth` полуходов из текущей позиции партии комнаты.
+/// Ответ — `Perft` или ошибка.
+pub struct Perft {
+    pub room: String,
+    pub depth: u32,
+}
+
+impl actix::Message for Perft {
+    type Result = ServerEvent;
+}
+
+/// Подсказать ход стороне, которая ходит в партии комнаты. Ответ — `Hint` или ошибка.
+pub struct Hint {
+    pub room: String,
+}
+
+impl actix::Message for Hint {
+    type Result = ServerEvent;
+}
+
+/// Получить сводку по всему серверу
+pub struct ServerStats;
+
+impl actix::Message for ServerStats {
+    type Result = ServerEvent;
+}
+
+/// Узнать, какая сторона сейчас ходит в комнате. `None`, если партия в комнате не начата.
+pub struct GetTurn {
+    pub room: String,
+}
+
+impl actix::Message for GetTurn {
+    type Result = Option<Color>;
+}
+
+/// Узнать, куда может пойти фигура с поля в партии комнаты. `None`, если партия в комнате
+/// не начата.
+pub struct GetMoves {
+    pub room: String,
+    pub from: Square,
+}
+
+impl actix::Message for GetMoves {
+    type Result = Option<Vec<Square>>;
+}
+
+/// Получить ходы партии комнаты в SAN. `None`, если партия в комнате не начата.
+pub struct GetHistory {
+    pub room: String,
+}
+
+impl actix::Message for GetHistory {
+    type Result = Option<Vec<String>>;
+}
+
+/// Получить партию комнаты в PGN. `None`, если партия в комнате не начата.
+pub struct GetPgn {
+    pub room: String,
+}
+
+impl actix::Message for GetPgn {
+    type Result = Option<String>;
+}
+
+/// `ChatServer` управляет чатами и отвечает за координацию чат-сессий.
+///
+/// Implementation is very naïve.
+#[derive(Debug)]
+pub struct ChatServer {
+    /// Комната, в которую попадает каждая новая сессия и из которой нельзя выйти
+    lobby: String,
+    sessions: HashMap<usize, Recipient<Message>>,
+    /// Адреса, по которым сессиям сообщают о полученном месте за доской
+    seated: HashMap<usize, Recipient<Seated>>,
+    rooms: HashMap<String, HashSet<usize>>,
+    /// Имена, которые сессии себе выбрали
+    names: HashMap<usize, String>,
+    /// Какой стороной к себе сессии хотят видеть доску
+    orientations: HashMap<usize, Color>,
+    games: HashMap<String, Game>,
+    /// Номер последней начатой партии
+    next_game_id: u64,
+    /// Законченные партии
+    archive: Archive,
+    /// Игроки, которые не хотят получать сообщения зрителей
+    muted_spectators: HashSet<usize>,
+    /// Рейтинги игроков по именам
+    ratings: HashMap<String, f64>,
+    /// Хеши паролей закрытых комнат в формате PHC
+    passwords: HashMap<String, String>,
+    /// Токены переподключения и сессии, которым они выданы
+    tokens: HashMap<Uuid, usize>,
+    /// Сессии, ждущие соперника для быстрой партии
+    queue: Vec<(usize, Recipient<Matched>)>,
+    /// Отключившиеся игроки, чьи места ещё ждут их, и таймеры ожидания
+    reserved: HashMap<usize, SpawnHandle>,
+    /// Зрители каждой комнаты, которые ждут места за доской, от ждущих дольше всех
+    seat_queue: HashMap<String, Vec<usize>>,
+    /// Таймеры сроков хода в партиях по переписке по комнатам
+    deadlines: HashMap<String, SpawnHandle>,
+    /// Настройки новых партий, если при создании комнаты их не задали
+    game_config: GameConfig,
+    /// Источник случайности для номеров сессий, расстановок и ходов бота; с заданным
+    /// зерном партии с ботом повторяются
+    rng: StdRng,
+    visitor_count: Arc<AtomicUsize>,
+    /// Число посетителей, которое ещё не разослано
+    pending_visitors: Option<usize>,
+    /// Входы в лобби и выходы из него, которые ещё не разосланы, и сессии, о которых они
+    pending_presence: Vec<(usize, ServerEvent)>,
+    /// Когда в комнате последний раз ходили, писали в чат или входили
+    last_activity: HashMap<String, Instant>,
+    /// Собственный адрес сервера, чтобы отключать недоступные сессии; слабый, чтобы
+    /// не удерживать сервер
+    address: Option<WeakAddr<ChatServer>>,
+    /// Сколько комнат, считая лобби, может быть на сервере одновременно
+    max_rooms: usize,
+    /// Сколько сообщений чата выброшено для каждой сессии, потому что её почтовый ящик
+    /// был полон; сессия узнаёт об этом со следующим сообщением
+    dropped_chat: RefCell<HashMap<usize, usize>>,
+    /// Сервер останавливается: новые партии не начинаются, и ходы не принимаются
+    shutting_down: bool,
+}
+
+impl ChatServer {
+    /// Сервер, в котором новые сессии попадают в комнату `lobby`, а всего может быть
+    /// не больше `max_rooms` комнат
+    pub fn new(
+        visitor_count: Arc<AtomicUsize>,
+        lobby: impl Into<String>,
+        max_rooms: usize,
+    ) -> ChatServer {
+        // комната по умолчанию
+        let lobby = lobby.into();
+        let mut rooms = HashMap::new();
+        rooms.insert(lobby.clone(), HashSet::new());
+
+        ChatServer {
+            lobby,
+            sessions: HashMap::new(),
+            seated: HashMap::new(),
+            rooms,
+            names: HashMap::new(),
+            orientations: HashMap::new(),
+            games: HashMap::new(),
+            next_game_id: 0,
+            archive: Archive::new(ARCHIVE_CAPACITY),
+            muted_spectators: HashSet::new(),
+            ratings: HashMap::new(),
+            passwords: HashMap::new(),
+            tokens: HashMap::new(),
+            queue: Vec::new(),
+            reserved: HashMap::new(),
+            seat_queue: HashMap::new(),
+            deadlines: HashMap::new(),
+            game_config: GameConfig::default(),
+            rng: StdRng::from_entropy(),
+            visitor_count,
+            pending_visitors: None,
+            pending_presence: Vec::new(),
+            last_activity: HashMap::new(),
+            address: None,
+            max_rooms,
+            dropped_chat: RefCell::default(),
+            shutting_down: false,
+        }
+    }
+
+    /// Сервер, вся случайность которого выводится из `seed`
+    pub fn with_seed(mut self, seed: u64) -> ChatServer {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+}
+
+impl ChatServer {
+    /// Отправить сообщение всем пользователям в комнате
+    fn send_message(&self, room: &str, event: &ServerEvent, skip_id: usize) {
+        self.send_message_where(room, event, skip_id, |_| true);
+    }
+
+    /// Сообщить комнате, что сессия в неё вошла
+    fn send_join(&mut self, room: &str, id: usize) {
+        let name = self.names.get(&id).cloned();
+        self.send_presence(room, id, ServerEvent::Join { id, name });
+    }
+
+    /// Сообщить комнате, что за доску сели оба игрока и партия началась
+    fn send_game_start(&self, room: &str) {
+        if let Some(game) = self.games.get(room) {
+            let name = |player: Option<usize>| player.and_then(|id| self.names.get(&id).cloned());
+            let event = ServerEvent::GameStart {
+                white: name(game.white),
+                black: name(game.black),
+                fen: game.board.to_fen(),
+            };
+            self.send_message(room, &event, 0);
+        }
+    }
+
+    /// Сообщить комнате, что сессия из неё вышла
+    fn send_leave(&mut self, room: &str, id: usize, name: Option<String>) {
+        self.send_presence(room, id, ServerEvent::Leave { id, name });
+    }
+
+    /// Разослать вход или выход сессии `id`. Игровая комната узнаёт о нём сразу, а лобби —
+    /// вместе с числом посетителей. Если сессия успела и войти в лобби, и выйти из него,
+    /// лобби не узнаёт ни о том, ни о другом.
+    fn send_presence(&mut self, room: &str, id: usize, event: ServerEvent) {
+        if room != self.lobby {
+            self.send_message(room, &event, id);
+            return;
+        }
+        let pending = self
+            .pending_presence
+            .iter()
+            .position(|(other, _)| *other == id);
+        match pending {
+            // повторный вход или выход ничего не меняет
+            Some(index)
+                if std::mem::discriminant(&self.pending_presence[index].1)
+                    == std::mem::discriminant(&event) => {}
+            Some(index) => {
+                self.pending_presence.remove(index);
+            }
+            None => self.pending_presence.push((id, event)),
+        }
+    }
+
+    /// Разослать лобби накопившиеся входы и выходы и число посетителей, если оно изменилось
+    /// с прошлой рассылки
+    fn flush_visitors(&mut self) {
+        for (id, event) in std::mem::take(&mut self.pending_presence) {
+            self.send_message(&self.lobby, &event, id);
+        }
+        if let Some(count) = self.pending_visitors.take() {
+            self.send_message(&self.lobby, &ServerEvent::Visitors { count }, 0);
+        }
+    }
+
+    /// Отправить сообщение тем пользователям комнаты, которых пропускает `filter`
+    fn send_message_where(
+        &self,
+        room: &str,
+        event: &ServerEvent,
+        skip_id: usize,
+        filter: impl Fn(usize) -> bool,
+    ) {
+        if let Some(sessions) = self.rooms.get(room) {
+            let message = Message::from(event);
+            for id in sessions {
+                if *id != skip_id && filter(*id) {
+                    self.deliver(*id, message.clone());
+                }
+            }
+        }
+    }
+
+    /// Проверить часы всех идущих партий: у кого закончилось время, тот проиграл,
+    /// остальным комнатам рассылается оставшееся время
+    fn check_clocks(&mut self) {
+        let now = Instant::now();
+        let mut updates = Vec::new();
+
+        for (room, game) in &mut self.games {
+            // у партии по переписке свой таймер на срок хода, см. `schedule_deadline`
+            if !game.clock.is_running() || game.clock.is_per_move() {
+                continue;
+            }
+            if let Some(loser) = game.clock.flagged(now) {
+                game.finish(Outcome::Timeout { loser }, now);
+            }
+            updates.push((room.clone(), game.clock_status(now), game.outcome));
+        }
+
+        for (room, status, outcome) in updates {
+            self.send_message(&room, &status, 0);
+            if let Some(outcome) = outcome {
+                self.send_message(&room, &outcome.into(), 0);
+                self.record_result(&room);
+            }
+        }
+    }
+
+    /// Поставить таймер на срок хода в партии комнаты по переписке вместо прежнего. Такие
+    /// партии не проверяются каждую секунду: срок проверяется один раз, когда он истечёт.
+    /// Комната сразу узнаёт, сколько времени осталось.
+    fn schedule_deadline(&mut self, room: &str, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.deadlines.remove(room) {
+            ctx.cancel_future(handle);
+        }
+        let game = match self.games.get(room) {
+            Some(game) if game.clock.is_per_move() && game.clock.is_running() => game,
+            _ => return,
+        };
+        let now = Instant::now();
+        let remaining = game.clock.remaining(game.turn(), now);
+        self.send_message(room, &game.clock_status(now), 0);
+
+        let name = room.to_owned();
+        let handle = ctx.run_later(remaining, move |act, ctx| act.check_deadline(&name, ctx));
+        self.deadlines.insert(room.to_owned(), handle);
+    }
+
+    /// Срок хода в партии комнаты по переписке истёк: не успевшая сторона проигрывает
+    fn check_deadline(&mut self, room: &str, ctx: &mut Context<Self>) {
+        self.deadlines.remove(room);
+        let now = Instant::now();
+        let outcome = self.games.get_mut(room).and_then(|game| {
+            let loser = game.clock.flagged(now)?;
+            let outcome = Outcome::Timeout { loser };
+            game.finish(outcome, now);
+            Some(outcome)
+        });
+        match outcome {
+            Some(outcome) => {
+                self.send_message(room, &outcome.into(), 0);
+                self.record_result(room);
+            }
+            // таймер сработал чуть раньше срока
+            None => self.schedule_deadline(room, ctx),
+        }
+    }
+
+    /// Закрыть игровые комнаты, в которых слишком долго ничего не происходило: незаконченная
+    /// партия прерывается, а комната вместе с партией удаляется. Лобби не закрывается.
+    fn sweep_idle_rooms(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<String> = self
+            .last_activity
+            .iter()
+            .filter(|(room, since)| {
+                **room != self.lobby && now.saturating_duration_since(**since) >= IDLE_TIMEOUT
+            })
+            // партия по переписке может ждать хода днями
+            .filter(|(room, _)| {
+                !self
+                    .games
+                    .get(*room)
+                    .is_some_and(|game| !game.is_over() && game.clock.is_per_move())
+            })
+            .map(|(room, _)| room.clone())
+            .collect();
+
+        for room in idle {
+            let aborted = self.games.get_mut(&room).is_some_and(|game| {
+                let running = !game.is_over();
+                if running {
+                    game.finish(Outcome::Inactivity, now);
+                }
+                running
+            });
+            if aborted {
+                self.send_message(&room, &Outcome::Inactivity.into(), 0);
+                self.record_result(&room);
+            }
+
+            log::info!("closing idle room {}", room);
+            self.last_activity.remove(&room);
+            self.rooms.remove(&room);
+            self.games.remove(&room);
+            self.passwords.remove(&room);
+            self.seat_queue.remove(&room);
+        }
+    }
+
+    /// Отметить, что в комнате что-то произошло
+    fn touch(&mut self, room: &str) {
+        if self.rooms.contains_key(room) {
+            self.last_activity.insert(room.to_owned(), Instant::now());
+        }
+    }
+
+    /// Отправить сессии её место в партии комнаты, позицию и очередь хода
+    /// или итог уже законченной партии
+    fn send_game_state(&self, id: usize, room: &str) {
+        let game = match self.games.get(room) {
+            Some(game) => game,
+            None => return,
+        };
+
+        match game.color_of(id) {
+            Some(color) => self.send_to(id, &ServerEvent::Color { color }),
+            None => self.send_to(
+                id,
+                &ServerEvent::Role {
+                    role: "spectator".to_owned(),
+                },
+            ),
+        }
+        self.send_to(
+            id,
+            &ServerEvent::Position {
+                fen: game.board.to_fen(),
+            },
+        );
+        if !game.captured_white.is_empty() || !game.captured_black.is_empty() {
+            self.send_to(id, &game.material());
+        }
+        match game.outcome {
+            Some(outcome) => self.send_to(id, &outcome.into()),
+            None => self.send_to(id, &ServerEvent::Turn { color: game.turn() }),
+        }
+    }
+
+    /// Случайный идентификатор, которого нет ни у подключённой сессии, ни у отключившегося
+    /// игрока, чьё место ещё ждёт его. Ноль не выдаётся: в `send_message` он означает,
+    /// что пропускать некого.
+    fn new_session_id(&mut self) -> usize {
+        loop {
+            let id = self.rng.gen::<usize>();
+            let free = !self.sessions.contains_key(&id) && !self.reserved.contains_key(&id);
+            if id != 0 && id != BOT_ID && free {
+                return id;
+            }
+        }
+    }
+
+    /// Добавить сессию в комнату, не забирая её из прежних. В игровой комнате сессия
+    /// садится за свободную сторону или становится зрителем.
+    fn enter_room(&mut self, id: usize, name: String, spectate: bool) {
+        self.rooms.entry(name.clone()).or_default().insert(id);
+        self.touch(&name);
+
+        self.send_join(&name, id);
+
+        // в игровой комнате сессия садится за свободную сторону или становится зрителем
+        if name != self.lobby {
+            let game = self.game_entry(&name);
+            let seated = !spectate && game.seat(id).is_some();
+            let started = seated && game.begin(Instant::now());
+
+            // новичок сразу получает позицию и очередь хода или итог уже законченной партии
+            self.send_game_state(id, &name);
+            if !seated {
+                self.send_spectators(&name);
+            }
+            if started {
+                self.send_game_start(&name);
+            }
+        }
+    }
+
+    /// Удалить сессию отовсюду: из комнат, партий и списка токенов
+    fn remove_session(&mut self, id: usize) {
+        let mut rooms: Vec<String> = Vec::new();
+
+        let name = self.names.remove(&id);
+        self.orientations.remove(&id);
+        self.muted_spectators.remove(&id);
+        self.tokens.retain(|_, session| *session != id);
+        for queue in self.seat_queue.values_mut() {
+            queue.retain(|&waiting| waiting != id);
+        }
+
+        // удалить сессию из всех помещений
+        for (name, sessions) in &mut self.rooms {
+            if sessions.remove(&id) {
+                rooms.push(name.to_owned());
+            }
+        }
+        // зрители, в отличие от игроков, меняют число зрителей своих партий
+        let watched: Vec<String> = rooms
+            .iter()
+            .filter(|room| {
+                self.games
+                    .get(*room)
+                    .is_some_and(|game| game.color_of(id).is_none())
+            })
+            .cloned()
+            .collect();
+        // освободить места в партиях
+        let now = Instant::now();
+        for game in self.games.values_mut() {
+            game.leave(id, now);
+        }
+        // отправлять сообщения другим пользователям
+        for room in rooms {
+            self.send_leave(&room, id, name.clone());
+        }
+        for room in watched {
+            self.send_spectators(&room);
+        }
+    }
+
+    /// Сколько в комнате зрителей: сессий, которые не сидят за доской. `None`, если в комнате
+    /// нет партии, как в лобби.
+    fn spectator_count(&self, room: &str) -> Option<usize> {
+        let game = self.games.get(room)?;
+        Some(self.rooms.get(room).map_or(0, |sessions| {
+            sessions
+                .iter()
+                .filter(|&&id| game.color_of(id).is_none())
+                .count()
+        }))
+    }
+
+    /// Разослать игровой комнате новое число зрителей
+    fn send_spectators(&self, room: &str) {
+        if let Some(count) = self.spectator_count(room) {
+            self.send_message(room, &ServerEvent::Spectators { count }, 0);
+        }
+    }
+
+    /// Отключившийся игрок не вернулся вовремя: в незаконченных партиях ему засчитывается
+    /// поражение по времени, а сессия удаляется
+    fn expire(&mut self, id: usize) {
+        self.reserved.remove(&id);
+
+        let now = Instant::now();
+        let mut outcomes = Vec::new();
+        let mut promoted = Vec::new();
+        for (room, game) in &mut self.games {
+            // в партии по переписке у игрока ещё есть срок на ход, чтобы вернуться
+            if game.is_over() || game.clock.is_per_move() {
+                continue;
+            }
+            let color = match game.color_of(id) {
+                Some(color) => color,
+                None => continue,
+            };
+
+            // место занимает зритель, который ждёт его дольше всех и ещё смотрит партию
+            let watching = |waiting: &usize| {
+                self.sessions.contains_key(waiting)
+                    && self
+                        .rooms
+                        .get(room)
+                        .is_some_and(|sessions| sessions.contains(waiting))
+                    && game.color_of(*waiting).is_none()
+            };
+            let queue = self.seat_queue.entry(room.clone()).or_default();
+            let next = queue.iter().position(watching).map(|i| queue.remove(i));
+            match next {
+                Some(next) => {
+                    match color {
+                        Color::White => game.white = Some(next),
+                        Color::Black => game.black = Some(next),
+                    }
+                    if game.premove.is_some_and(|(side, _)| side == color) {
+                        game.premove = None;
+                    }
+                    if game.config.seat_clock == SeatClock::Fresh {
+                        game.clock.reset(color, now);
+                    }
+                    promoted.push((room.clone(), next, color));
+                }
+                None => {
+                    let outcome = Outcome::Timeout { loser: color };
+                    game.finish(outcome, now);
+                    outcomes.push((room.clone(), outcome));
+                }
+            }
+        }
+        for (room, outcome) in outcomes {
+            self.send_message(&room, &outcome.into(), 0);
+            self.record_result(&room);
+        }
+
+        self.remove_session(id);
+
+        for (room, next, color) in promoted {
+            if let Some(seated) = self.seated.get(&next) {
+                seated.do_send(Seated { room: room.clone() });
+            }
+            self.send_to(next, &ServerEvent::Promoted { color });
+            self.send_message(
+                &room,
+                &ServerEvent::system(format!("a spectator now plays {}", color)),
+                next,
+            );
+        }
+    }
+
+    /// Заняты ли все места для комнат: новую комнату создать нельзя, в существующие
+    /// входить можно
+    fn at_capacity(&self) -> bool {
+        self.rooms.len() >= self.max_rooms
+    }
+
+    /// Если сервер останавливается, сообщить об этом сессии `id` и вернуть `true`
+    fn refuse_while_shutting_down(&self, id: usize) -> bool {
+        if self.shutting_down {
+            self.send_to(id, &shutting_down());
+        }
+        self.shutting_down
+    }
+
+    /// Случайное название ещё не существующей комнаты
+    fn random_room(&mut self, prefix: &str) -> String {
+        loop {
+            let room = format!("{}-{:08x}", prefix, self.rng.gen::<u32>());
+            if !self.rooms.contains_key(&room) {
+                return room;
+            }
+        }
+    }
+
+    /// Новый номер партии
+    fn new_game_id(&mut self) -> GameId {
+        self.next_game_id += 1;
+        GameId(self.next_game_id)
+    }
+
+    /// Новая партия с настройками `config`. Начальная позиция зависит от варианта: `board`,
+    /// а если её нет, обычная расстановка, либо случайная расстановка шахмат Фишера.
+    fn new_game(&mut self, board: Option<Board>, config: GameConfig) -> Game {
+        let start = match config.variant {
+            Variant::Standard => board.unwrap_or_default(),
+            Variant::Chess960 => Board::chess960(self.rng.gen_range(0..CHESS960_POSITIONS)),
+        };
+        let id = self.new_game_id();
+        Game::new(id, start, config)
+    }
+
+    /// Партия комнаты; если её ещё нет, начинается новая с настройками сервера
+    fn game_entry(&mut self, room: &str) -> &mut Game {
+        if !self.games.contains_key(room) {
+            let game = self.new_game(None, self.game_config);
+            self.games.insert(room.to_owned(), game);
+        }
+        self.games.get_mut(room).expect("game was just inserted")
+    }
+
+    /// Записать итог законченной партии комнаты: сохранить её в архив, сообщить комнате
+    /// её номер и пересчитать рейтинги игроков
+    fn record_result(&mut self, room: &str) {
+        let game = match self.games.get(room) {
+            Some(game) => game,
+            None => return,
+        };
+        let outcome = match game.outcome {
+            Some(outcome) => outcome,
+            None => return,
+        };
+        let name = |player: Option<usize>| player.and_then(|id| self.names.get(&id).cloned());
+
+        let id = game.id;
+        let white = name(game.white);
+        let black = name(game.black);
+        // ничья до первого хода и прерванная партия ничего не говорят о силе игроков
+        let rated = game.config.rated
+            && !outcome.is_aborted()
+            && !(outcome.winner().is_none() && game.history.is_empty());
+        // подозрительно быстрая игра только записывается в журнал для проверки человеком
+        if rated {
+            for color in [Color::White, Color::Black] {
+                let streak = game.fast_reply_streak(color, FAST_REPLY, HINT_DEPTH);
+                let player = game.player(color).filter(|&player| player != BOT_ID);
+                if let (true, Some(player)) = (streak >= SUSPICIOUS_STREAK, player) {
+                    log::warn!(
+                        "session {} in room {}: {} best moves in a row under {} ms in game {} as {}",
+                        player,
+                        room,
+                        streak,
+                        FAST_REPLY.as_millis(),
+                        id,
+                        color
+                    );
+                }
+            }
+        }
+        let archived = ArchivedGame {
+            white: white.clone(),
+            black: black.clone(),
+            outcome,
+            start: game.start.clone(),
+            moves: game.history.clone(),
+            berserk: [Color::White, Color::Black]
+                .into_iter()
+                .filter(|color| game.berserk.contains(color))
+                .collect(),
+        };
+        self.archive.insert(id, archived);
+        self.send_message(room, &ServerEvent::Archived { id }, 0);
+
+        // рейтинг ведётся по именам, поэтому безымянные игроки не учитываются
+        if let (true, Some(white), Some(black)) = (rated, white, black) {
+            if white != black {
+                self.update_ratings(room, white, black, outcome);
+            }
+        }
+    }
+
+    /// Пересчитать рейтинги игроков по итогу партии и разослать их комнате
+    fn update_ratings(&mut self, room: &str, white: String, black: String, outcome: Outcome) {
+        let white_score = match outcome.winner() {
+            Some(Color::White) => 1.0,
+            Some(Color::Black) => 0.0,
+            None => 0.5,
+        };
+        let (white_rating, black_rating) =
+            rating::update(self.rating(&white), self.rating(&black), white_score);
+        self.ratings.insert(white.clone(), white_rating);
+        self.ratings.insert(black.clone(), black_rating);
+
+        let ratings = BTreeMap::from([(white, white_rating), (black, black_rating)]);
+        self.send_message(room, &ServerEvent::Ratings { ratings }, 0);
+    }
+
+    /// Рейтинг игрока; у нового игрока он начальный
+    fn rating(&self, name: &str) -> f64 {
+        self.ratings
+            .get(name)
+            .copied()
+            .unwrap_or(rating::INITIAL_RATING)
+    }
+
+    /// Партия комнаты, в которой сессия играет, и цвет сессии в ней
+    fn player_game(&mut self, room: &str, id: usize) -> Option<(&mut Game, Color)> {
+        let game = self.games.get_mut(room)?;
+        let color = game.color_of(id)?;
+        Some((game, color))
+    }
+
+    /// Отправить сообщение одной сессии
+    fn send_to(&self, id: usize, event: &ServerEvent) {
+        self.deliver(id, event.into());
+    }
+
+    /// Передать сериализованное событие сессии. Если её почтовый ящик уже закрыт, сокет
+    /// закрылся, не сообщив об этом, и сессия отключается, чтобы не занимать место в комнатах.
+    fn deliver(&self, id: usize, mut message: Message) {
+        let addr = match self.sessions.get(&id) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let mut dropped_chat = self.dropped_chat.borrow_mut();
+        message.dropped = dropped_chat.remove(&id).unwrap_or(0);
+        match addr.try_send(message) {
+            Ok(()) => {}
+            // сессия не успевает разбирать ящик: чат для неё выбрасывается, а не копится
+            Err(SendError::Full(message)) if message.chat => {
+                *dropped_chat.entry(id).or_default() += message.dropped + 1;
+            }
+            // игровые события не выбрасываются: переполненный ящик не значит, что сессии нет
+            Err(SendError::Full(message)) => addr.do_send(message),
+            Err(SendError::Closed(_)) => {
+                log::warn!("session {} is unreachable, disconnecting it", id);
+                if let Some(server) = self.address.as_ref().and_then(WeakAddr::upgrade) {
+                    server.do_send(Disconnect { id, clean: false });
+                }
+            }
+        }
+    }
+}
+
+/// Чем закончилась партия в текущей позиции: мат или пат стороне, которая ходит, нехватка
+/// материала, запертые пешки или пятьдесят ходов без взятий. `None`, если партию можно
+/// продолжать.
+fn position_outcome(game: &Game) -> Option<Outcome> {
+    let side = game.turn();
+    if rules::is_checkmate(&game.board, side) {
+        Some(Outcome::Checkmate {
+            winner: side.opposite(),
+        })
+    } else if rules::is_stalemate(&game.board, side) {
+        Some(Outcome::Stalemate)
+    } else if rules::is_insufficient_material(&game.board) {
+        Some(Outcome::InsufficientMaterial)
+    } else if rules::is_dead_position(&game.board) {
+        Some(Outcome::DeadPosition)
+    } else if game.config.automatic_fifty_move && game.board.halfmove_clock() >= FIFTY_MOVE_PLIES {
+        Some(Outcome::FiftyMove)
+    } else {
+        None
+    }
+}
+
+/// Ошибка для запросов, которые сервер не выполняет, пока останавливается
+fn shutting_down() -> ServerEvent {
+    ServerEvent::error("shutting_down", "server is shutting down")
+}
+
+/// Допустимый ход позиции по записи из `/chess_step`. Ход в координатной нотации приходит
+/// уже в каноническом виде, остальное — SAN. При отказе — код ошибки и объяснение.
+fn find_move(board: &Board, step: &str) -> Result<Move, (&'static str, String)> {
+    match Move::parse(step) {
+        Some(mv) => rules::check_legal(board, &mv)
+            .map(|_| mv)
+            .map_err(|err| (err.code(), format!("{}: {}", err, step))),
+        None => san::parse_san(board, step).map_err(|err| (err.code(), err.to_string())),
+    }
+}
+
+/// Хеш пароля комнаты со случайной солью
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("hashing with default parameters cannot fail")
+        .to_string()
+}
+
+/// Обрезать строку до `max` символов. Срез по байтам мог бы попасть в середину
+/// многобайтового символа UTF-8, поэтому граница ищется по символам. `true`, если строку
+/// пришлось обрезать.
+fn truncate_on_char_boundary(s: &mut String, max: usize) -> bool {
+    match s.char_indices().nth(max) {
+        Some((end, _)) => {
+            s.truncate(end);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Совпадает ли пароль с сохранённым хешем
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Сделать актера из `ChatServer`
+impl Actor for ChatServer {
+    /// Мы собираемся использовать простой Context, нам просто нужна способность общаться с другими актерами.
+    type Context = Context<Self>;
+
+    /// При старте запускаем проверку шахматных часов, подобно сердцебиению сессий,
+    /// и рассылку числа посетителей
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.address = Some(ctx.address().downgrade());
+        ctx.run_interval(CLOCK_INTERVAL, |act, _| act.check_clocks());
+        ctx.run_interval(VISITORS_INTERVAL, |act, _| act.flush_visitors());
+        ctx.run_interval(IDLE_SWEEP_INTERVAL, |act, _| act.sweep_idle_rooms());
+    }
+}
+
+/// Обработчик для сообщения Connect.
+///
+/// Зарегистрируйте новую сессию и присвойте ей уникальный идентификатор
+impl Handler<Connect> for ChatServer {
+    type Result = MessageResult<Connect>;
+
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) -> Self::Result {
+        // вернувшийся игрок получает прежний идентификатор, комнату и место за доской
+        let returning = msg
+            .reconnect
+            .and_then(|token| self.tokens.get(&token).copied())
+            .filter(|id| self.reserved.contains_key(id));
+        if let Some(id) = returning {
+            log::info!("session {} reconnected", id);
+            if let Some(handle) = self.reserved.remove(&id) {
+                ctx.cancel_future(handle);
+            }
+            self.sessions.insert(id, msg.addr);
+            self.seated.insert(id, msg.seated);
+
+            let rooms: HashSet<String> = self
+                .rooms
+                .iter()
+                .filter(|(_, sessions)| sessions.contains(&id))
+                .map(|(name, _)| name.clone())
+                .collect();
+            // соседи по комнатам узнают, что игрок вернулся, а не ушёл; сосед по нескольким
+            // комнатам узнаёт об этом один раз
+            let neighbours: HashSet<usize> = rooms
+                .iter()
+                .filter_map(|room| self.rooms.get(room))
+                .flatten()
+                .copied()
+                .filter(|&other| other != id)
+                .collect();
+            for neighbour in neighbours {
+                self.send_to(neighbour, &ServerEvent::Reconnected { id });
+            }
+            for room in &rooms {
+                self.send_game_state(id, room);
+            }
+            // текущей становится одна из партий, если сессия где-то играет
+            let room = rooms
+                .iter()
+                .find(|room| **room != self.lobby)
+                .cloned()
+                .unwrap_or_else(|| self.lobby.clone());
+
+            return MessageResult(Connected {
+                id,
+                room,
+                rooms,
+                lobby: self.lobby.clone(),
+                name: self.names.get(&id).cloned(),
+                orientation: self.orientations.get(&id).copied(),
+            });
+        }
+
+        let lobby = self.lobby.clone();
+
+        // зарегистрировать сессию со случайным идентификатором
+        let id = self.new_session_id();
+        // уведомлять всех пользователей в одной комнате
+        self.send_join(&lobby, id);
+        self.sessions.insert(id, msg.addr);
+        self.seated.insert(id, msg.seated);
+        log::info!("session {} joined", id);
+
+        let token = Uuid::new_v4();
+        self.tokens.insert(token, id);
+        self.send_to(id, &ServerEvent::Session { token });
+
+        // автоматическое присоединение сеанса к главной комнате
+        self.rooms.entry(lobby.clone()).or_default().insert(id);
+
+        // число посетителей рассылается не чаще раза в VISITORS_INTERVAL;
+        // fetch_add возвращает значение до прибавления
+        let count = self.visitor_count.fetch_add(1, Ordering::SeqCst) + 1;
+        self.pending_visitors = Some(count);
+
+        // отправить идентификатор обратно
+        MessageResult(Connected {
+            id,
+            room: lobby.clone(),
+            rooms: HashSet::from([lobby.clone()]),
+            lobby,
+            name: None,
+            orientation: None,
+        })
+    }
+}
+
+/// Обработчик сообщения об отключении.
+impl Handler<Disconnect> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        // удалить адрес; сессия могла уже отключиться раньше
+        if self.sessions.remove(&msg.id).is_none() {
+            return;
+        }
+        self.seated.remove(&msg.id);
+        self.dropped_chat.get_mut().remove(&msg.id);
+        log::info!("session {} disconnected (clean: {})", msg.id, msg.clean);
+        self.queue.retain(|(queued, _)| *queued != msg.id);
+
+        // соперник в незаконченной партии узнаёт, ушёл игрок сам или у него пропала связь
+        let opponents: Vec<usize> = self
+            .games
+            .values()
+            .filter(|game| !game.is_over())
+            .filter_map(|game| game.player(game.color_of(msg.id)?.opposite()))
+            .filter(|&opponent| opponent != msg.id && opponent != BOT_ID)
+            .collect();
+        for opponent in opponents {
+            self.send_to(opponent, &ServerEvent::OpponentLeft { clean: msg.clean });
+        }
+
+        // место игрока незаконченной партии ждёт его возвращения
+        let id = msg.id;
+        let playing = self
+            .games
+            .values()
+            .any(|game| !game.is_over() && game.color_of(id).is_some());
+        if playing {
+            let handle = ctx.run_later(RECONNECT_GRACE, move |act, _| act.expire(id));
+            self.reserved.insert(id, handle);
+            return;
+        }
+
+        self.remove_session(id);
+    }
+}
+
+/// Обработчик для сообщения Message.
+impl Handler<ClientMessage> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, mut msg: ClientMessage, _: &mut Context<Self>) {
+        self.touch(&msg.room);
+        let game = self.games.get(&msg.room);
+        let is_player = |id: usize| game.is_some_and(|game| game.color_of(id).is_some());
+        let from_spectator = game.is_some() && !is_player(msg.id);
+
+        if msg.scope != ChatScope::All && game.is_none() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_game", "no game in this room"),
+            );
+            return;
+        }
+        if msg.scope == ChatScope::Players && from_spectator {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("spectator", "spectators cannot write to players"),
+            );
+            return;
+        }
+
+        // игроки, отключившие чат зрителей, не получают от зрителей ничего
+        let muted = |id: usize| from_spectator && self.muted_spectators.contains(&id);
+        if truncate_on_char_boundary(&mut msg.msg, MAX_CHAT_LENGTH) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::system(format!(
+                    "your message was truncated to {} characters",
+                    MAX_CHAT_LENGTH
+                )),
+            );
+        }
+        let event = ServerEvent::Chat { text: msg.msg };
+        match msg.scope {
+            ChatScope::All => self.send_message_where(&msg.room, &event, msg.id, |id| !muted(id)),
+            ChatScope::Players => self.send_message_where(&msg.room, &event, msg.id, is_player),
+            ChatScope::Spectators => {
+                self.send_message_where(&msg.room, &event, msg.id, |id| !is_player(id))
+            }
+        }
+    }
+}
+
+/// Обработчик хода: ход применяется к доске комнаты и рассылается, только если
+/// его прислал игрок, чья сейчас очередь, и ход допустим.
+impl Handler<ChessGame> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChessGame, ctx: &mut Context<Self>) {
+        if self.refuse_while_shutting_down(msg.id) {
+            return;
+        }
+        // в лобби партии нет: ход там некуда сделать
+        let game = match self.games.get_mut(&msg.room) {
+            Some(game) => game,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("no_game", "no active game in this room"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+
+        if game.color_of(msg.id).is_none() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("spectator", "spectators cannot move"),
+            );
+            return;
+        }
+
+        // с номером полухода повторно присланный или устаревший ход не сделается ещё раз;
+        // в комнате со строгой нумерацией ход без номера не принимается
+        let expected = game.history.len() + 1;
+        if game.config.strict_ply && msg.ply.is_none() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error(
+                    "ply_required",
+                    format!(
+                        "move must include its ply number: expected ply {}",
+                        expected
+                    ),
+                ),
+            );
+            return;
+        }
+        if msg.ply.is_some_and(|ply| ply != expected) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error(
+                    "stale_move",
+                    format!("stale move: expected ply {}", expected),
+                ),
+            );
+            return;
+        }
+
+        // в одиночной партии сессия ходит за обе стороны
+        if game.player(game.turn()) != Some(msg.id) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("not_your_turn", "not your turn"),
+            );
+            return;
+        }
+
+        let mv = match find_move(&game.board, &msg.step) {
+            Ok(mv) => mv,
+            Err((code, message)) => {
+                self.send_to(msg.id, &ServerEvent::error(code, message));
+                return;
+            }
+        };
+
+        // ход, сделанный после падения флага, уже не засчитывается
+        let now = Instant::now();
+        if let Some(loser) = game.clock.flagged(now) {
+            let outcome = Outcome::Timeout { loser };
+            game.finish(outcome, now);
+            self.send_message(&msg.room, &outcome.into(), 0);
+            self.record_result(&msg.room);
+            return;
+        }
+
+        // подробности хода для клиента нужно узнать до того, как он изменит доску
+        let rook = game.board.castling_rook(&mv);
+        let captured = game.board.en_passant_capture(&mv);
+        let capture = game.board.captured_piece(&mv).is_some();
+        // ход вместо ответа отклоняет ничью, которую предложил соперник
+        let mover = game.turn();
+        let declined = if game.draw_offer == Some(mover.opposite()) {
+            game.draw_offer = None;
+            game.player(mover.opposite())
+        } else {
+            None
+        };
+        if msg.premove {
+            game.make_premove(mv, now);
+        } else {
+            game.make_move(mv, now);
+        }
+        let san = game.history.last().cloned().unwrap_or_default();
+        let ply = game.history.len();
+        let elapsed_ms = game.times.last().map_or(0, |time| time.elapsed.as_millis());
+
+        // после хода очередь уже у соперника: проверяем, не получил ли он мат, пат или шах
+        let opponent = game.turn();
+        let check = rules::is_in_check(&game.board, opponent);
+        let outcome = position_outcome(game);
+        if let Some(outcome) = outcome {
+            game.finish(outcome, now);
+        }
+        let fen = game.board.to_fen();
+        let material = game.material();
+
+        self.send_message(
+            &msg.room,
+            &ServerEvent::Move {
+                mv: mv.to_string(),
+                from: mv.from.to_string(),
+                to: mv.to.to_string(),
+                san,
+                promotion: mv.promotion,
+                rook: rook.map(|(from, to)| SquareMove {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                }),
+                captured: captured.map(|square| square.to_string()),
+                elapsed_ms,
+            },
+            msg.id,
+        );
+        self.touch(&msg.room);
+        self.schedule_deadline(&msg.room, ctx);
+        // сходившему вместо хода приходит подтверждение, что ход принят
+        self.send_to(
+            msg.id,
+            &ServerEvent::MoveAck {
+                mv: mv.to_string(),
+                ply,
+            },
+        );
+        self.send_message(&msg.room, &ServerEvent::Position { fen }, 0);
+        if capture {
+            self.send_message(&msg.room, &material, 0);
+        }
+        if let Some(offerer) = declined {
+            self.send_to(offerer, &ServerEvent::DrawDeclined);
+        }
+
+        if let Some(outcome) = outcome {
+            self.send_message(&msg.room, &outcome.into(), 0);
+            self.record_result(&msg.room);
+        } else if check {
+            self.send_message(&msg.room, &ServerEvent::Check { side: opponent }, 0);
+        }
+
+        // отложенный ход соперника делается сразу, если он допустим в новой позиции
+        let premove = self
+            .games
+            .get_mut(&msg.room)
+            .filter(|game| !game.is_over())
+            .and_then(|game| {
+                let (color, premove) = game.premove.take()?;
+                let player = game.player(color)?;
+                let legal = color == opponent && rules::check_legal(&game.board, &premove).is_ok();
+                Some((player, premove, legal))
+            });
+        match premove {
+            Some((player, premove, true)) => {
+                ctx.notify(ChessGame {
+                    id: player,
+                    step: premove.to_string(),
+                    room: msg.room,
+                    ply: Some(ply + 1),
+                    premove: true,
+                });
+                return;
+            }
+            Some((player, _, false)) => self.send_to(player, &ServerEvent::PremoveCancelled),
+            None => {}
+        }
+
+        // если дальше ходит бот, он отвечает таким же ходом, как у игроков
+        let reply = self
+            .games
+            .get(&msg.room)
+            .filter(|game| !game.is_over() && game.player(opponent) == Some(BOT_ID))
+            .and_then(|game| bot::random_move(&game.board, opponent, &mut self.rng));
+        if let Some(reply) = reply {
+            ctx.notify(ChessGame {
+                id: BOT_ID,
+                step: reply.to_string(),
+                room: msg.room,
+                ply: Some(ply + 1),
+                premove: false,
+            });
+        }
+    }
+}
+
+/// Обработчик отложенного хода: пока ходит соперник, ход запоминается, по одному на игрока;
+/// в свою очередь игрок просто ходит.
+impl Handler<Premove> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Premove, ctx: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.turn() == color || game.player(game.turn()) == Some(msg.id) {
+            ctx.notify(ChessGame {
+                id: msg.id,
+                step: msg.step,
+                room: msg.room,
+                ply: Some(game.history.len() + 1),
+                premove: false,
+            });
+            return;
+        }
+
+        let premove = match Move::parse(&msg.step) {
+            Some(premove) => premove,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("malformed_move", format!("malformed move: {}", msg.step)),
+                );
+                return;
+            }
+        };
+        game.premove = Some((color, premove));
+        self.send_to(msg.id, &ServerEvent::system(format!("premove {}", premove)));
+    }
+}
+
+/// Обработчик сдачи: партия заканчивается поражением сдавшегося игрока.
+impl Handler<Resign> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Resign, _: &mut Context<Self>) {
+        let (game, loser) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+
+        let outcome = Outcome::Resign { loser };
+        game.finish(outcome, Instant::now());
+        self.send_message(&msg.room, &outcome.into(), 0);
+        self.record_result(&msg.room);
+    }
+}
+
+/// Обработчик прерывания партии: партия без ходов заканчивается без результата и без
+/// изменения рейтингов, а оба места за доской освобождаются.
+impl Handler<Abort> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Abort, _: &mut Context<Self>) {
+        let (game, _) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if !game.history.is_empty() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("cannot_abort", "cannot abort after first move; use /resign"),
+            );
+            return;
+        }
+
+        game.finish(Outcome::Aborted, Instant::now());
+        self.send_message(&msg.room, &Outcome::Aborted.into(), 0);
+        self.record_result(&msg.room);
+        if let Some(game) = self.games.get_mut(&msg.room) {
+            game.white = None;
+            game.black = None;
+        }
+    }
+}
+
+/// Обработчик предложения ничьей: предложение ждёт ответа соперника.
+impl Handler<OfferDraw> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: OfferDraw, _: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if !game.can_offer_draw(color) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("draw_cooldown", "draw offer on cooldown"),
+            );
+            return;
+        }
+
+        game.offer_draw(color);
+        if let Some(opponent) = game.player(color.opposite()) {
+            self.send_to(opponent, &ServerEvent::DrawOffered);
+        }
+        self.send_to(msg.id, &ServerEvent::system("draw offered"));
+    }
+}
+
+/// Обработчик согласия на ничью: партия заканчивается ничьей по соглашению.
+impl Handler<AcceptDraw> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AcceptDraw, _: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.draw_offer != Some(color.opposite()) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_draw_offer", "no draw offered"),
+            );
+            return;
+        }
+
+        game.finish(Outcome::Agreement, Instant::now());
+        self.send_message(&msg.room, &Outcome::Agreement.into(), 0);
+        self.record_result(&msg.room);
+    }
+}
+
+/// Обработчик предложения другого контроля времени: как и ничья, оно ждёт ответа
+/// соперника, но только до первого хода.
+impl Handler<ProposeTime> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProposeTime, _: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if !game.history.is_empty() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error(
+                    "game_started",
+                    "time control can only change before the first move",
+                ),
+            );
+            return;
+        }
+
+        game.time_offer = Some((color, msg.time_control));
+        if let Some(opponent) = game.player(color.opposite()) {
+            self.send_to(
+                opponent,
+                &ServerEvent::TimeOffered {
+                    time_control: msg.time_control.map(Into::into),
+                },
+            );
+        }
+        self.send_to(msg.id, &ServerEvent::system("time control proposed"));
+    }
+}
+
+/// Обработчик согласия на другой контроль времени: часы обеих сторон сбрасываются
+/// на новый контроль.
+impl Handler<AcceptTime> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AcceptTime, ctx: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if !game.history.is_empty() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error(
+                    "game_started",
+                    "time control can only change before the first move",
+                ),
+            );
+            return;
+        }
+        let time_control = match game.time_offer {
+            Some((side, time_control)) if side == color.opposite() => time_control,
+            _ => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("no_time_offer", "no time control proposed"),
+                );
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        game.set_time_control(time_control, now);
+        let clock = time_control.map(|_| game.clock_status(now));
+        self.send_message(
+            &msg.room,
+            &ServerEvent::TimeChanged {
+                time_control: time_control.map(Into::into),
+            },
+            0,
+        );
+        if let Some(clock) = clock {
+            self.send_message(&msg.room, &clock, 0);
+        }
+        self.schedule_deadline(&msg.room, ctx);
+    }
+}
+
+/// Обработчик отказа от ничьей: предложение снимается, соперник узнаёт об отказе.
+impl Handler<DeclineDraw> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeclineDraw, _: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.draw_offer != Some(color.opposite()) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_draw_offer", "no draw offered"),
+            );
+            return;
+        }
+
+        game.draw_offer = None;
+        if let Some(opponent) = game.player(color.opposite()) {
+            self.send_to(opponent, &ServerEvent::DrawDeclined);
+        }
+    }
+}
+
+/// Обработчик для сообщения `GetDrawOffer`.
+impl Handler<GetDrawOffer> for ChatServer {
+    type Result = Option<Color>;
+
+    fn handle(&mut self, msg: GetDrawOffer, _: &mut Context<Self>) -> Self::Result {
+        self.games
+            .get(&msg.room)
+            .filter(|game| !game.is_over())
+            .and_then(|game| game.draw_offer)
+    }
+}
+
+/// Обработчик требования ничьей: ничья засчитывается, если уже сделано пятьдесят ходов
+/// без взятий и ходов пешками или текущая позиция повторилась трижды.
+impl Handler<ClaimDraw> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClaimDraw, _: &mut Context<Self>) {
+        let (game, _) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+
+        let outcome = if game.board.halfmove_clock() >= FIFTY_MOVE_PLIES {
+            Outcome::FiftyMove
+        } else if game.repetition_count() >= 3 {
+            Outcome::Repetition
+        } else {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_draw_to_claim", "no draw to claim"),
+            );
+            return;
+        };
+        game.finish(outcome, Instant::now());
+        self.send_message(&msg.room, &outcome.into(), 0);
+        self.record_result(&msg.room);
+    }
+}
+
+/// Обработчик предложения вернуть ход: предложение пересылается сопернику.
+impl Handler<OfferTakeback> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: OfferTakeback, _: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.history.is_empty() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_moves", "no moves to take back"),
+            );
+            return;
+        }
+        if game.takebacks_left(color) == 0 {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("takeback_limit", "takeback limit reached"),
+            );
+            return;
+        }
+
+        game.takeback_offer = Some(color);
+        if let Some(opponent) = game.player(color.opposite()) {
+            self.send_to(opponent, &ServerEvent::TakebackOffered);
+        }
+        self.send_to(msg.id, &ServerEvent::system("takeback offered"));
+    }
+}
+
+/// Обработчик для сообщения `Berserk`: время игрока уменьшается вдвое, добавка за ход
+/// пропадает, и комната получает новое время на часах.
+impl Handler<Berserk> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Berserk, ctx: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.config.time_control.is_none() {
+            self.send_to(msg.id, &ServerEvent::error("no_clock", "game has no clock"));
+            return;
+        }
+        if game.has_moved(color) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error(
+                    "already_moved",
+                    "berserk is only allowed before your first move",
+                ),
+            );
+            return;
+        }
+        if !game.berserk.insert(color) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("already_berserk", "you have already gone berserk"),
+            );
+            return;
+        }
+
+        let now = Instant::now();
+        game.clock.berserk(color, now);
+        let clock = game.clock_status(now);
+        self.send_message(&msg.room, &ServerEvent::Berserk { color }, 0);
+        self.send_message(&msg.room, &clock, 0);
+        self.schedule_deadline(&msg.room, ctx);
+    }
+}
+
+/// Обработчик согласия вернуть ход: последний ход отменяется, и комната получает
+/// новую позицию.
+impl Handler<AcceptTakeback> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: AcceptTakeback, ctx: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.takeback_offer != Some(color.opposite()) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_takeback_offer", "no takeback offered"),
+            );
+            return;
+        }
+        if !game.take_back(Instant::now()) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("no_moves", "no moves to take back"),
+            );
+            return;
+        }
+
+        let fen = game.board.to_fen();
+        let turn = game.turn();
+        let material = game.material();
+        self.send_message(&msg.room, &ServerEvent::Takeback, 0);
+        self.send_message(&msg.room, &ServerEvent::Position { fen }, 0);
+        self.send_message(&msg.room, &ServerEvent::Turn { color: turn }, 0);
+        self.send_message(&msg.room, &material, 0);
+        self.schedule_deadline(&msg.room, ctx);
+    }
+}
+
+/// Обработчик отладочного отражения позиции: позиция партии отражается, и комната
+/// получает новую. Отражать могут только игроки, и не в рейтинговой партии.
+impl Handler<Mirror> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Mirror, ctx: &mut Context<Self>) {
+        let game = match self.player_game(&msg.room, msg.id) {
+            Some((game, _)) => game,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.config.rated {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("rated", "cannot mirror a rated game"),
+            );
+            return;
+        }
+
+        game.mirror(Instant::now());
+        let fen = game.board.to_fen();
+        let turn = game.turn();
+        self.send_message(&msg.room, &ServerEvent::Position { fen }, 0);
+        self.send_message(&msg.room, &ServerEvent::Turn { color: turn }, 0);
+        self.schedule_deadline(&msg.room, ctx);
+    }
+}
+
+/// Обработчик просьбы о новой партии: когда её просят оба игрока, они меняются цветами
+/// и начинают партию заново. Бот соглашается сразу.
+impl Handler<Rematch> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Rematch, ctx: &mut Context<Self>) {
+        if self.refuse_while_shutting_down(msg.id) {
+            return;
+        }
+        let id = self.new_game_id();
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if !game.is_over() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("game_not_over", "game is not over"),
+            );
+            return;
+        }
+        let opponent = game.player(color.opposite());
+        if game.rematch_offer != Some(color.opposite()) && opponent != Some(BOT_ID) {
+            game.rematch_offer = Some(color);
+            if let Some(opponent) = opponent {
+                self.send_to(opponent, &ServerEvent::RematchOffered);
+            }
+            self.send_to(msg.id, &ServerEvent::system("rematch requested"));
+            return;
+        }
+
+        game.rematch(id, Instant::now());
+        let fen = game.board.to_fen();
+        let players = [game.white, game.black];
+        let board = (game.white == Some(BOT_ID)).then(|| game.board.clone());
+        let bot_move =
+            board.and_then(|board| bot::random_move(&board, Color::White, &mut self.rng));
+        self.send_message(&msg.room, &ServerEvent::Restart, 0);
+        self.send_message(&msg.room, &ServerEvent::Position { fen }, 0);
+        for (player, color) in players.into_iter().zip([Color::White, Color::Black]) {
+            if let Some(player) = player {
+                self.send_to(player, &ServerEvent::Color { color });
+            }
+        }
+        self.schedule_deadline(&msg.room, ctx);
+
+        if let Some(reply) = bot_move {
+            ctx.notify(ChessGame {
+                id: BOT_ID,
+                step: reply.to_string(),
+                room: msg.room,
+                ply: Some(1),
+                premove: false,
+            });
+        }
+    }
+}
+
+/// Обработчик просьбы зрителя о месте: зритель садится за свободную сторону, если она есть.
+impl Handler<Promote> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Promote, ctx: &mut Context<Self>) {
+        let game = match self.games.get_mut(&msg.room) {
+            Some(game) => game,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("no_game", "no game in this room"),
+                );
+                return;
+            }
+        };
+
+        if game.color_of(msg.id).is_some() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("already_player", "you are already a player"),
+            );
+            return;
+        }
+
+        let color = match game.seat(msg.id) {
+            Some(color) => color,
+            None => {
+                self.send_to(msg.id, &ServerEvent::error("no_free_seat", "no free seat"));
+                return;
+            }
+        };
+        let started = game.begin(Instant::now());
+
+        self.send_to(msg.id, &ServerEvent::Color { color });
+        self.send_message(
+            &msg.room,
+            &ServerEvent::system(format!("a spectator now plays {}", color)),
+            msg.id,
+        );
+        self.send_spectators(&msg.room);
+        if started {
+            self.send_game_start(&msg.room);
+        }
+        self.schedule_deadline(&msg.room, ctx);
+    }
+}
+
+/// Обработчик для сообщения `Flag`: часы соперника проверяются сейчас же, не дожидаясь
+/// очередной проверки всех часов.
+impl Handler<Flag> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Flag, _: &mut Context<Self>) {
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.config.time_control.is_none() {
+            self.send_to(msg.id, &ServerEvent::error("no_clock", "game has no clock"));
+            return;
+        }
+        let now = Instant::now();
+        let opponent = color.opposite();
+        if game.clock.flagged(now) != Some(opponent) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("not_flagged", "opponent has not flagged"),
+            );
+            return;
+        }
+
+        let outcome = Outcome::Timeout { loser: opponent };
+        game.finish(outcome, now);
+        self.send_message(&msg.room, &outcome.into(), 0);
+        self.record_result(&msg.room);
+    }
+}
+
+/// Обработчик для сообщения `WantSeat`: зритель встаёт в очередь комнаты на место
+/// игрока, который отключится и не вернётся.
+impl Handler<WantSeat> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: WantSeat, _: &mut Context<Self>) {
+        let game = match self.games.get(&msg.room) {
+            Some(game) => game,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("no_game", "no game in this room"),
+                );
+                return;
+            }
+        };
+
+        if game.color_of(msg.id).is_some() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("already_player", "you are already a player"),
+            );
+            return;
+        }
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if game.white.is_none() || game.black.is_none() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("seat_free", "a seat is free, use /promote"),
+            );
+            return;
+        }
+        let queue = self.seat_queue.entry(msg.room).or_default();
+        if queue.contains(&msg.id) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("already_queued", "already waiting for a seat"),
+            );
+            return;
+        }
+
+        queue.push(msg.id);
+        self.send_to(msg.id, &ServerEvent::system("waiting for a seat"));
+    }
+}
+
+/// Обработчик для сообщения `PassSeat`: зритель получает цвет и часы игрока, игрок
+/// становится зрителем, и комната узнаёт об обмене.
+impl Handler<PassSeat> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: PassSeat, _: &mut Context<Self>) {
+        let in_room = self
+            .rooms
+            .get(&msg.room)
+            .is_some_and(|sessions| sessions.contains(&msg.to));
+        let (game, color) = match self.player_game(&msg.room, msg.id) {
+            Some(found) => found,
+            None => {
+                self.send_to(
+                    msg.id,
+                    &ServerEvent::error("not_a_player", "you are not a player"),
+                );
+                return;
+            }
+        };
+
+        if game.is_over() {
+            self.send_to(msg.id, &ServerEvent::error("game_over", "game is over"));
+            return;
+        }
+        if !in_room || game.color_of(msg.to).is_some() || msg.to == BOT_ID {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("not_a_spectator", "no such spectator in this room"),
+            );
+            return;
+        }
+
+        match color {
+            Color::White => game.white = Some(msg.to),
+            Color::Black => game.black = Some(msg.to),
+        }
+        // отложенный ход делал уходящий игрок
+        if game.premove.is_some_and(|(side, _)| side == color) {
+            game.premove = None;
+        }
+
+        if let Some(seated) = self.seated.get(&msg.to) {
+            seated.do_send(Seated {
+                room: msg.room.clone(),
+            });
+        }
+        self.send_to(msg.to, &ServerEvent::Color { color });
+        self.send_to(
+            msg.id,
+            &ServerEvent::Role {
+                role: "spectator".to_owned(),
+            },
+        );
+        self.send_message(
+            &msg.room,
+            &ServerEvent::SeatPassed {
+                color,
+                from: msg.id,
+                to: msg.to,
+            },
+            0,
+        );
+    }
+}
+
+/// Обработчик очереди быстрых партий: вторая сессия в очереди играет с первой
+/// в новой комнате со случайным названием, цвета распределяются случайно.
+impl Handler<QuickMatch> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: QuickMatch, _: &mut Context<Self>) {
+        if self.refuse_while_shutting_down(msg.id) {
+            return;
+        }
+        if self.queue.iter().any(|(queued, _)| *queued == msg.id) {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("already_queued", "already waiting for an opponent"),
+            );
+            return;
+        }
+        // пара получила бы новую комнату, а места для неё нет
+        if !self.queue.is_empty() && self.at_capacity() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("at_capacity", "server at capacity"),
+            );
+            return;
+        }
+        if self.queue.is_empty() {
+            self.queue.push((msg.id, msg.addr));
+            self.send_to(msg.id, &ServerEvent::system("waiting for an opponent"));
+            return;
+        }
+
+        let mut players = [self.queue.remove(0), (msg.id, msg.addr)];
+        if self.rng.gen::<bool>() {
+            players.swap(0, 1);
+        }
+
+        let room = self.random_room("quick");
+
+        // первый вошедший садится за белых
+        for (id, addr) in players {
+            self.enter_room(id, room.clone(), false);
+            if let Some(color) = self.games.get(&room).and_then(|game| game.color_of(id)) {
+                addr.do_send(Matched {
+                    room: room.clone(),
+                    color,
+                });
+            }
+        }
+    }
+}
+
+/// Обработчик партии против бота: сессия играет белыми в новой комнате, за чёрных
+/// садится бот.
+impl Handler<PlayBot> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: PlayBot, _: &mut Context<Self>) {
+        if self.refuse_while_shutting_down(msg.id) {
+            return;
+        }
+        if self.at_capacity() {
+            self.send_to(
+                msg.id,
+                &ServerEvent::error("at_capacity", "server at capacity"),
+            );
+            return;
+        }
+        let room = self.random_room("bot");
+        self.enter_room(msg.id, room.clone(), false);
+        self.names.insert(BOT_ID, BOT_NAME.to_owned());
+
+        let game = self.game_entry(&room);
+        game.black = Some(BOT_ID);
+        let started = game.begin(Instant::now());
+
+        if let Some(color) = game.color_of(msg.id) {
+            msg.addr.do_send(Matched {
+                room: room.clone(),
+                color,
+            });
+        }
+        if started {
+            self.send_game_start(&room);
+        }
+    }
+}
+
+/// Обработчик для сообщения `GetTurn`.
+impl Handler<GetTurn> for ChatServer {
+    type Result = Option<Color>;
+
+    fn handle(&mut self, msg: GetTurn, _: &mut Context<Self>) -> Self::Result {
+        self.games.get(&msg.room).map(Game::turn)
+    }
+}
+
+/// Обработчик для сообщения `GetStatus`.
+impl Handler<GetStatus> for ChatServer {
+    type Result = Option<ServerEvent>;
+
+    fn handle(&mut self, msg: GetStatus, _: &mut Context<Self>) -> Self::Result {
+        self.games.get(&msg.room).map(Game::status)
+    }
+}
+
+/// Обработчик для сообщения `CheckMove`: ход проверяется, но доска не меняется, поэтому
+/// спросить может и зритель.
+impl Handler<CheckMove> for ChatServer {
+    type Result = MessageResult<CheckMove>;
+
+    fn handle(&mut self, msg: CheckMove, _: &mut Context<Self>) -> Self::Result {
+        let game = match self.games.get(&msg.room) {
+            Some(game) => game,
+            None => {
+                return MessageResult(ServerEvent::error("no_game", "no active game in this room"))
+            }
+        };
+        let reason = match game.outcome {
+            Some(_) => Some("game is over".to_owned()),
+            None => find_move(&game.board, &msg.step)
+                .err()
+                .map(|(_, reason)| reason),
+        };
+        MessageResult(ServerEvent::MoveCheck {
+            mv: msg.step,
+            legal: reason.is_none(),
+            reason,
+        })
+    }
+}
+
+/// Обработчик для сообщения `Hint`: как и проверка хода, подсказка доступна и зрителю.
+impl Handler<Hint> for ChatServer {
+    type Result = MessageResult<Hint>;
+
+    fn handle(&mut self, msg: Hint, _: &mut Context<Self>) -> Self::Result {
+        let game = match self.games.get(&msg.room) {
+            Some(game) => game,
+            None => {
+                return MessageResult(ServerEvent::error("no_game", "no active game in this room"))
+            }
+        };
+        if game.is_over() {
+            return MessageResult(ServerEvent::error("game_over", "game is over"));
+        }
+        MessageResult(match search::best_move(&game.board, HINT_DEPTH) {
+            Some(mv) => ServerEvent::Hint { mv: mv.to_string() },
+            None => ServerEvent::error("no_moves", "no legal moves"),
+        })
+    }
+}
+
+/// Обработчик для сообщения `Perft`.
+impl Handler<Perft> for ChatServer {
+    type Result = MessageResult<Perft>;
+
+    fn handle(&mut self, msg: Perft, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(match self.games.get(&msg.room) {
+            Some(game) => ServerEvent::Perft {
+                depth: msg.depth,
+                nodes: rules::perft(&game.board, msg.depth),
+            },
+            None => ServerEvent::error("no_game", "no active game in this room"),
+        })
+    }
+}
+
+/// Обработчик для сообщения `ServerStats`.
+impl Handler<ServerStats> for ChatServer {
+    type Result = MessageResult<ServerStats>;
+
+    fn handle(&mut self, _: ServerStats, _: &mut Context<Self>) -> Self::Result {
+        let finished = self.games.values().filter(|game| game.is_over()).count();
+        MessageResult(ServerEvent::Stats {
+            sessions: self.sessions.len(),
+            game_rooms: self
+                .rooms
+                .keys()
+                .filter(|room| self.games.contains_key(*room))
+                .count(),
+            games_in_progress: self.games.len() - finished,
+            games_finished: finished,
+            visitors: self.visitor_count.load(Ordering::SeqCst),
+        })
+    }
+}
+
+/// Обработчик для сообщения `GetMoves`.
+impl Handler<GetMoves> for ChatServer {
+    type Result = Option<Vec<Square>>;
+
+    fn handle(&mut self, msg: GetMoves, _: &mut Context<Self>) -> Self::Result {
+        self.games
+            .get(&msg.room)
+            .map(|game| rules::legal_moves_from(&game.board, msg.from))
+    }
+}
+
+/// Обработчик для сообщения `GetHistory`.
+impl Handler<GetHistory> for ChatServer {
+    type Result = Option<Vec<String>>;
+
+    fn handle(&mut self, msg: GetHistory, _: &mut Context<Self>) -> Self::Result {
+        self.games.get(&msg.room).map(|game| game.history.clone())
+    }
+}
+
+/// Обработчик для сообщения `GetPgn`.
+impl Handler<GetPgn> for ChatServer {
+    type Result = Option<String>;
+
+    fn handle(&mut self, msg: GetPgn, _: &mut Context<Self>) -> Self::Result {
+        let game = self.games.get(&msg.room)?;
+        let name =
+            |player: Option<usize>| player.and_then(|id| self.names.get(&id).map(String::as_str));
+
+        Some(pgn::to_pgn(
+            game,
+            &msg.room,
+            name(game.white),
+            name(game.black),
+        ))
+    }
+}
+
+/// Обработчик для сообщения `PlayerList`.
+impl Handler<PlayerList> for ChatServer {
+    type Result = Option<ServerEvent>;
+
+    fn handle(&mut self, msg: PlayerList, _: &mut Context<Self>) -> Self::Result {
+        let game = self.games.get(&msg.room)?;
+        // безымянный игрок всё же сидит за доской, пустое место остаётся `null`
+        let name = |player: Option<usize>| {
+            player.map(|id| {
+                self.names
+                    .get(&id)
+                    .cloned()
+                    .unwrap_or_else(|| "anonymous".to_owned())
+            })
+        };
+        Some(ServerEvent::Players {
+            white: name(game.white),
+            black: name(game.black),
+            spectators: self.spectator_count(&msg.room).unwrap_or(0),
+        })
+    }
+}
+
+/// Обработчик для сообщения `GetArchived`.
+impl Handler<GetArchived> for ChatServer {
+    type Result = Option<ServerEvent>;
+
+    fn handle(&mut self, msg: GetArchived, _: &mut Context<Self>) -> Self::Result {
+        let game = self.archive.get(msg.id)?;
+        Some(ServerEvent::ArchivedGame {
+            id: msg.id,
+            white: game.white.clone(),
+            black: game.black.clone(),
+            result: game.outcome.result().to_owned(),
+            reason: game.outcome.reason().to_owned(),
+            moves: game.moves.clone(),
+            berserk: game.berserk.clone(),
+        })
+    }
+}
+
+/// Обработчик для сообщения `GetReplay`.
+impl Handler<GetReplay> for ChatServer {
+    type Result = Option<Vec<ServerEvent>>;
+
+    fn handle(&mut self, msg: GetReplay, _: &mut Context<Self>) -> Self::Result {
+        let game = self.archive.get(msg.id)?;
+        let mut board = game.start.clone();
+        let mut events = vec![ServerEvent::Replay {
+            id: msg.id,
+            fen: board.to_fen(),
+        }];
+        for (ply, san) in game.moves.iter().enumerate() {
+            // ходы архива были допустимы, когда их делали, так что разбор не должен сорваться
+            let mv = match san::parse_san(&board, san) {
+                Ok(mv) => mv,
+                Err(err) => {
+                    log::warn!("cannot replay game {}: {}", msg.id, err);
+                    break;
+                }
+            };
+            board.apply(mv);
+            events.push(ServerEvent::ReplayMove {
+                ply: ply + 1,
+                san: san.clone(),
+                fen: board.to_fen(),
+            });
+        }
+        Some(events)
+    }
+}
+
+/// Обработчик для сообщения `WhoAmI`.
+impl Handler<WhoAmI> for ChatServer {
+    type Result = MessageResult<WhoAmI>;
+
+    fn handle(&mut self, msg: WhoAmI, _: &mut Context<Self>) -> Self::Result {
+        let game = self.games.get(&msg.room);
+        let color = game.and_then(|game| game.color_of(msg.id));
+        // в комнате без партии у сессии нет роли
+        let role = game.map(|_| match color {
+            Some(_) => "player".to_owned(),
+            None => "spectator".to_owned(),
+        });
+
+        MessageResult(ServerEvent::Whoami {
+            id: msg.id,
+            name: self.names.get(&msg.id).cloned(),
+            room: msg.room,
+            color,
+            role,
+            orientation: self.orientations.get(&msg.id).copied(),
+        })
+    }
+}
+
+/// Обработчик для сообщения `GetRating`.
+impl Handler<GetRating> for ChatServer {
+    type Result = MessageResult<GetRating>;
+
+    fn handle(&mut self, msg: GetRating, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.rating(&msg.name))
+    }
+}
+
+/// Обработчик для сообщения `Leave`.
+///
+/// Игрок, покинувший идущую партию, проигрывает её, как при сдаче; если ходов ещё
+/// не было, партия ждёт нового игрока.
+impl Handler<Leave> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        let left = self
+            .rooms
+            .get_mut(&msg.room)
+            .is_some_and(|sessions| sessions.remove(&msg.id));
+        if let Some(queue) = self.seat_queue.get_mut(&msg.room) {
+            queue.retain(|&waiting| waiting != msg.id);
+        }
+        let spectator = self
+            .games
+            .get(&msg.room)
+            .is_some_and(|game| game.color_of(msg.id).is_none());
+
+        let outcome = self.games.get_mut(&msg.room).and_then(|game| {
+            let loser = game.color_of(msg.id)?;
+            if game.is_over() || game.history.is_empty() || game.player(loser.opposite()).is_none()
+            {
+                return None;
+            }
+            let outcome = Outcome::Resign { loser };
+            game.finish(outcome, Instant::now());
+            Some(outcome)
+        });
+        if let Some(outcome) = outcome {
+            self.send_message(&msg.room, &outcome.into(), 0);
+            self.record_result(&msg.room);
+        }
+        if let Some(game) = self.games.get_mut(&msg.room) {
+            game.leave(msg.id, Instant::now());
+        }
+        if left {
+            self.send_leave(&msg.room, msg.id, self.names.get(&msg.id).cloned());
+            if spectator {
+                self.send_spectators(&msg.room);
+            }
+        }
+    }
+}
+
+/// Обработчик для сообщения `MuteSpectators`.
+impl Handler<MuteSpectators> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: MuteSpectators, _: &mut Context<Self>) {
+        if msg.muted {
+            self.muted_spectators.insert(msg.id);
+        } else {
+            self.muted_spectators.remove(&msg.id);
+        }
+    }
+}
+
+/// Обработчик для сообщения `Shutdown`.
+impl Handler<Shutdown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Context<Self>) {
+        self.shutting_down = true;
+        let message = Message::from(&ServerEvent::system("server shutting down"));
+        for id in self.sessions.keys() {
+            self.deliver(*id, message.clone());
+        }
+
+        let now = Instant::now();
+        let mut aborted = Vec::new();
+        for (room, game) in &mut self.games {
+            if !game.is_over() {
+                game.finish(Outcome::Aborted, now);
+                aborted.push(room.clone());
+            }
+        }
+        for room in aborted {
+            self.send_message(&room, &Outcome::Aborted.into(), 0);
+            self.record_result(&room);
+        }
+        ctx.run_later(SHUTDOWN_NOTICE, |_, ctx| ctx.stop());
+    }
+}
+
+/// Обработчик для сообщения `SetName`.
+impl Handler<SetName> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetName, _: &mut Context<Self>) {
+        self.names.insert(msg.id, msg.name);
+    }
+}
+
+/// Обработчик для сообщения `SetOrientation`.
+impl Handler<SetOrientation> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetOrientation, _: &mut Context<Self>) {
+        self.orientations.insert(msg.id, msg.orientation);
+    }
+}
+
+/// Обработчик для сообщения `ListRooms`.
+impl Handler<ListRooms> for ChatServer {
+    type Result = MessageResult<ListRooms>;
+
+    fn handle(&mut self, _: ListRooms, _: &mut Context<Self>) -> Self::Result {
+        let mut rooms = Vec::new();
+
+        for key in self.rooms.keys() {
+            rooms.push(key.to_owned())
+        }
+
+        MessageResult(rooms)
+    }
+}
+
+/// Обработчик для сообщения `ListGames`: партии перечисляются по названиям комнат.
+impl Handler<ListGames> for ChatServer {
+    type Result = MessageResult<ListGames>;
+
+    fn handle(&mut self, _: ListGames, _: &mut Context<Self>) -> Self::Result {
+        let name = |player: Option<usize>| player.and_then(|id| self.names.get(&id).cloned());
+        let mut games: Vec<GameSummary> = self
+            .games
+            .iter()
+            .map(|(room, game)| GameSummary {
+                room: room.clone(),
+                id: game.id,
+                white: name(game.white),
+                black: name(game.black),
+                status: if game.is_over() {
+                    "finished"
+                } else {
+                    "in_progress"
+                },
+                result: game.outcome.map(|outcome| outcome.result().to_owned()),
+                moves: game.history.len(),
+                spectators: self.spectator_count(room).unwrap_or(0),
+                private: self.passwords.contains_key(room),
+            })
+            .collect();
+        games.sort_by(|a, b| a.room.cmp(&b.room));
+        MessageResult(games)
+    }
+}
+
+/// Присоединиться к комнате и отправить сообщение о присоединении в новую комнату; прежние комнаты сессия не покидает
+impl Handler<Join> for ChatServer {
+    type Result = Result<(), ServerEvent>;
+
+    fn handle(&mut self, msg: Join, ctx: &mut Context<Self>) -> Self::Result {
+        let Join {
+            id,
+            name,
+            password,
+            create,
+            board,
+            config,
+            moves,
+            spectate,
+            solo,
+        } = msg;
+
+        if self.shutting_down {
+            return Err(shutting_down());
+        }
+        if !self.rooms.contains_key(&name) && self.at_capacity() {
+            return Err(ServerEvent::error("at_capacity", "server at capacity"));
+        }
+        if create {
+            // комнату с паролем или заданной позицией можно только создать, но не перезаписать
+            if self.rooms.contains_key(&name) {
+                return Err(ServerEvent::error(
+                    "room_exists",
+                    format!("room {} already exists", name),
+                ));
+            }
+            if let Some(password) = password {
+                self.passwords
+                    .insert(name.clone(), hash_password(&password));
+            }
+        } else if let Some(hash) = self.passwords.get(&name) {
+            if !password.is_some_and(|password| verify_password(&password, hash)) {
+                return Err(ServerEvent::error("wrong_password", "wrong password"));
+            }
+        }
+
+        // новая комната получает новую партию; загруженная партия продолжается с позиции
+        // после своих ходов
+        if name != self.lobby && (create || !self.games.contains_key(&name)) {
+            let now = Instant::now();
+            let mut game = self.new_game(board, config.unwrap_or(self.game_config));
+            for mv in moves {
+                game.make_move(mv, now);
+            }
+            if let Some(outcome) = position_outcome(&game) {
+                game.finish(outcome, now);
+            }
+            self.games.insert(name.clone(), game);
+        }
+        self.enter_room(id, name.clone(), spectate);
+        if solo {
+            if let Some(game) = self.games.get_mut(&name) {
+                game.white = Some(id);
+                game.black = Some(id);
+                if game.begin(Instant::now()) {
+                    self.send_game_start(&name);
+                }
+            }
+        }
+        self.schedule_deadline(&name, ctx);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn server() -> ChatServer {
+        ChatServer::new(Arc::new(AtomicUsize::new(0)), "main", 16)
+    }
+
+    /// Сессия, которая только запоминает присланные ей события
+    struct Probe(Arc<Mutex<Vec<String>>>);
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Message> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, msg: Message, _: &mut Context<Self>) {
+            self.0.lock().unwrap().push(msg.text);
+        }
+    }
+
+    impl Handler<Seated> for Probe {
+        type Result = ();
+
+        fn handle(&mut self, _: Seated, _: &mut Context<Self>) {}
+    }
+
+    /// Подключить к серверу новую сессию и вернуть её вместе с ящиком её событий
+    fn connect(
+        server: &mut ChatServer,
+        ctx: &mut Context<ChatServer>,
+    ) -> (Connected, Arc<Mutex<Vec<String>>>) {
+        let inbox = Arc::new(Mutex::new(Vec::new()));
+        let probe = Probe(Arc::clone(&inbox)).start();
+        let connect = Connect {
+            addr: probe.clone().recipient(),
+            seated: probe.recipient(),
+            reconnect: None,
+        };
+        (server.handle(connect, ctx).0, inbox)
+    }
+
+    /// События, которые дошли до сессии с прошлого раза
+    async fn received(inbox: &Mutex<Vec<String>>) -> Vec<serde_json::Value> {
+        actix::clock::sleep(Duration::from_millis(20)).await;
+        inbox
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|text| serde_json::from_str(&text).unwrap())
+            .collect()
+    }
+
+    /// Последнее событие типа `kind`
+    fn last_of<'a>(events: &'a [serde_json::Value], kind: &str) -> Option<&'a serde_json::Value> {
+        events.iter().rev().find(|event| event["type"] == kind)
+    }
+
+    /// Игрок за доской: номер сессии и ящик её событий
+    type Player = (usize, Arc<Mutex<Vec<String>>>);
+
+    /// Создать комнату `room` с безрейтинговой партией без часов из позиции `fen` и посадить
+    /// за доску двух игроков: первый играет белыми
+    async fn seated_game(
+        server: &mut ChatServer,
+        ctx: &mut Context<ChatServer>,
+        room: &str,
+        fen: &str,
+    ) -> (Player, Player) {
+        let (white, white_inbox) = connect(server, ctx);
+        let (black, black_inbox) = connect(server, ctx);
+        let create = Join {
+            id: white.id,
+            name: room.to_owned(),
+            create: true,
+            board: Some(Board::from_fen(fen).unwrap()),
+            config: Some(GameConfig {
+                time_control: None,
+                rated: false,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        server.handle(create, ctx).unwrap();
+        let join = Join {
+            id: black.id,
+            name: room.to_owned(),
+            ..Default::default()
+        };
+        server.handle(join, ctx).unwrap();
+        received(&white_inbox).await;
+        received(&black_inbox).await;
+        ((white.id, white_inbox), (black.id, black_inbox))
+    }
+
+    /// Сделать ход от имени сессии `id`
+    fn step(
+        server: &mut ChatServer,
+        ctx: &mut Context<ChatServer>,
+        id: usize,
+        room: &str,
+        mv: &str,
+    ) {
+        let step = ChessGame {
+            id,
+            room: room.to_owned(),
+            step: mv.to_owned(),
+            ply: None,
+            premove: false,
+        };
+        server.handle(step, ctx);
+    }
+
+    #[actix::test]
+    async fn visitors_are_counted_after_increment() {
+        let mut server = server();
+        let mut ctx = Context::new();
+        let mut inboxes = Vec::new();
+        for expected in 1..=3 {
+            let (_, inbox) = connect(&mut server, &mut ctx);
+            server.flush_visitors();
+            inboxes.push(inbox);
+            // новую сессию и всех прежних извещают о новом числе посетителей
+            for inbox in &inboxes {
+                let events = received(inbox).await;
+                let visitors = last_of(&events, "visitors").expect("visitors event");
+                assert_eq!(visitors["count"], expected);
+            }
+        }
+    }
+
+    #[actix::test]
+    async fn lobby_presence_is_batched() {
+        let mut server = server();
+        let mut ctx = Context::new();
+        let (_, inbox) = connect(&mut server, &mut ctx);
+        server.flush_visitors();
+        received(&inbox).await;
+
+        let (brief, _) = connect(&mut server, &mut ctx);
+        let (stayed, _) = connect(&mut server, &mut ctx);
+        let disconnect = Disconnect {
+            id: brief.id,
+            clean: true,
+        };
+        server.handle(disconnect, &mut ctx);
+        // до рассылки лобби ничего не узнаёт
+        assert!(received(&inbox).await.is_empty());
+
+        // зашедшая и сразу ушедшая сессия не видна вовсе
+        server.flush_visitors();
+        let events = received(&inbox).await;
+        let presence: Vec<_> = events
+            .iter()
+            .filter(|event| event["type"] == "join" || event["type"] == "leave")
+            .collect();
+        assert_eq!(presence.len(), 1);
+        assert_eq!(presence[0]["type"], "join");
+        assert_eq!(presence[0]["id"], stayed.id);
+        assert!(last_of(&events, "visitors").is_some());
+    }
+
+    #[test]
+    fn random_room_skips_taken_names() {
+        // с тем же зерном первым выпадает то же название; занимаем его заранее
+        let taken = server().with_seed(7).random_room("quick");
+        let mut server = server().with_seed(7);
+        server.rooms.insert(taken.clone(), HashSet::new());
+
+        let room = server.random_room("quick");
+        assert_ne!(room, taken);
+        assert!(room.starts_with("quick-"));
+        assert!(!server.rooms.contains_key(&room));
+    }
+
+    #[actix::test]
+    async fn session_ids_skip_taken_ones() {
+        // с тем же зерном выпадают те же номера: первый занят подключённой сессией,
+        // второй ждёт отключившегося игрока
+        let mut seeded = server().with_seed(7);
+        let connected = seeded.new_session_id();
+        let waiting = seeded.new_session_id();
+
+        let mut server = server().with_seed(7);
+        let mut ctx = Context::new();
+        let existing = Probe(Arc::new(Mutex::new(Vec::new()))).start().recipient();
+        server.sessions.insert(connected, existing.clone());
+        let handle = ctx.spawn(actix::fut::ready(()));
+        server.reserved.insert(waiting, handle);
+
+        let (new, _) = connect(&mut server, &mut ctx);
+        assert_ne!(new.id, connected);
+        assert_ne!(new.id, waiting);
+        assert!(server.sessions[&connected] == existing);
+        assert!(!server.sessions.contains_key(&waiting));
+    }
+
+    #[actix::test]
+    async fn new_sessions_land_in_custom_lobby() {
+        let mut server = ChatServer::new(Arc::new(AtomicUsize::new(0)), "lobby", 16);
+        let mut ctx = Context::new();
+        let (connected, _) = connect(&mut server, &mut ctx);
+
+        assert_eq!(connected.room, "lobby");
+        assert_eq!(connected.lobby, "lobby");
+        assert!(connected.rooms.contains("lobby"));
+        assert!(server.rooms["lobby"].contains(&connected.id));
+        assert!(!server.rooms.contains_key("Main"));
+    }
+
+    #[actix::test]
+    async fn step_in_lobby_is_rejected() {
+        let mut server = server();
+        let mut ctx = Context::new();
+        let (connected, inbox) = connect(&mut server, &mut ctx);
+        let (_, other) = connect(&mut server, &mut ctx);
+        received(&inbox).await;
+        received(&other).await;
+
+        let step = ChessGame {
+            id: connected.id,
+            room: connected.room,
+            step: "e2e4".to_owned(),
+            ply: None,
+            premove: false,
+        };
+        server.handle(step, &mut ctx);
+
+        let events = received(&inbox).await;
+        let error = last_of(&events, "error").expect("error event");
+        assert_eq!(error["code"], "no_game");
+        assert_eq!(error["message"], "no active game in this room");
+        // ход никому не разослан
+        assert!(last_of(&received(&other).await, "move").is_none());
+    }
+
+    #[actix::test]
+    async fn room_creation_is_refused_at_capacity() {
+        // лобби и ещё две комнаты
+        let mut server = ChatServer::new(Arc::new(AtomicUsize::new(0)), "main", 3);
+        let mut ctx = Context::new();
+        let (first, _) = connect(&mut server, &mut ctx);
+        let (second, _) = connect(&mut server, &mut ctx);
+        let join = |id: usize, name: &str| Join {
+            id,
+            name: name.to_owned(),
+            ..Default::default()
+        };
+
+        assert!(server.handle(join(first.id, "a"), &mut ctx).is_ok());
+        assert!(server.handle(join(first.id, "b"), &mut ctx).is_ok());
+        assert!(server.at_capacity());
+
+        match server.handle(join(second.id, "c"), &mut ctx) {
+            Err(ServerEvent::Error { code, .. }) => assert_eq!(code, "at_capacity"),
+            other => panic!("expected at_capacity, got {:?}", other),
+        }
+        assert!(!server.rooms.contains_key("c"));
+        // в уже существующие комнаты входить можно
+        assert!(server.handle(join(second.id, "a"), &mut ctx).is_ok());
+    }
+
+    #[actix::test]
+    async fn perpetual_check_allows_repetition_claim() {
+        let mut server = server();
+        let mut ctx = Context::new();
+        let ((white, _), (black, black_inbox)) =
+            seated_game(&mut server, &mut ctx, "g", "8/6pk/8/8/8/8/8/K2Q4 w - - 0 1").await;
+        let claim = |id: usize| ClaimDraw {
+            id,
+            room: "g".to_owned(),
+        };
+
+        // ферзь шахует с h5 и e8, а королю некуда уйти, кроме h7 и g8
+        let round = [
+            (black, "h7g8"),
+            (white, "h5e8"),
+            (black, "g8h7"),
+            (white, "e8h5"),
+        ];
+        step(&mut server, &mut ctx, white, "g", "d1h5");
+        for (id, mv) in round {
+            step(&mut server, &mut ctx, id, "g", mv);
+        }
+        assert_eq!(server.games["g"].repetition_count(), 2);
+        server.handle(claim(black), &mut ctx);
+        let events = received(&black_inbox).await;
+        let error = last_of(&events, "error").expect("error event");
+        assert_eq!(error["code"], "no_draw_to_claim");
+        assert!(!server.games["g"].is_over());
+
+        for (id, mv) in round {
+            step(&mut server, &mut ctx, id, "g", mv);
+        }
+        assert_eq!(server.games["g"].repetition_count(), 3);
+
+        server.handle(claim(black), &mut ctx);
+        assert_eq!(server.games["g"].outcome, Some(Outcome::Repetition));
+        let events = received(&black_inbox).await;
+        let gameover = last_of(&events, "gameover").expect("gameover event");
+        assert_eq!(gameover["reason"], "repetition");
+    }
+
+    #[actix::test]
+    async fn restart_needs_both_players() {
+        let mut server = server();
+        let mut ctx = Context::new();
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let ((white, white_inbox), (black, _)) =
+            seated_game(&mut server, &mut ctx, "g", start).await;
+        let (spectator, spectator_inbox) = connect(&mut server, &mut ctx);
+        let join = Join {
+            id: spectator.id,
+            name: "g".to_owned(),
+            ..Default::default()
+        };
+        server.handle(join, &mut ctx).unwrap();
+        received(&spectator_inbox).await;
+        let restart = |id: usize| Rematch {
+            id,
+            room: "g".to_owned(),
+        };
+
+        step(&mut server, &mut ctx, white, "g", "e2e4");
+        let fen = server.games["g"].board.to_fen();
+
+        server.handle(restart(spectator.id), &mut ctx);
+        let events = received(&spectator_inbox).await;
+        assert_eq!(last_of(&events, "error").unwrap()["code"], "not_a_player");
+        server.handle(restart(white), &mut ctx);
+        let events = received(&white_inbox).await;
+        assert_eq!(last_of(&events, "error").unwrap()["code"], "game_not_over");
+        assert_eq!(server.games["g"].board.to_fen(), fen);
+
+        // после конца партии одного желающего мало: доска остаётся как была
+        server.handle(
+            Resign {
+                id: black,
+                room: "g".to_owned(),
+            },
+            &mut ctx,
+        );
+        server.handle(restart(white), &mut ctx);
+        server.handle(restart(spectator.id), &mut ctx);
+        assert!(server.games["g"].is_over());
+        assert_eq!(server.games["g"].board.to_fen(), fen);
+        assert_eq!(server.games["g"].white, Some(white));
+    }
+
+    #[actix::test]
+    async fn move_declines_pending_draw_offer() {
+        let mut server = server();
+        let mut ctx = Context::new();
+        let start = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+        let ((white, white_inbox), (black, black_inbox)) =
+            seated_game(&mut server, &mut ctx, "g", start).await;
+
+        step(&mut server, &mut ctx, white, "g", "e2e4");
+        let offer = OfferDraw {
+            id: white,
+            room: "g".to_owned(),
+        };
+        server.handle(offer, &mut ctx);
+        assert!(last_of(&received(&black_inbox).await, "draw_offered").is_some());
+        received(&white_inbox).await;
+
+        // ответный ход отклоняет предложение, и предложившему об этом сообщают
+        step(&mut server, &mut ctx, black, "g", "e7e5");
+        assert_eq!(server.games["g"].draw_offer, None);
+        assert!(last_of(&received(&white_inbox).await, "draw_declined").is_some());
+        assert!(last_of(&received(&black_inbox).await, "draw_declined").is_none());
+    }
+}
@@ -1,30 +1,277 @@
-use std::time::{Duration, Instant};
+use std::{
+    collections::{HashSet, VecDeque},
+    pin::Pin,
+    task::{self, Poll},
+    time::{Duration, Instant},
+};
 
 use actix::prelude::*;
+use actix_http::ws::Item;
 use actix_web_actors::ws;
+use uuid::Uuid;
 
-use crate::server;
+use crate::{
+    board::{Board, Color, Move, Square},
+    command::{ChatScope, ClientCommand},
+    event::ServerEvent,
+    game::{GameConfig, GameId, Variant},
+    pgn,
+    protocol::{self, ProtocolError},
+    rate_limit::TokenBucket,
+    server,
+};
 
-/// Как часто отправляются пинги сердцебиения
-const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Как часто по умолчанию отправляются пинги сердцебиения
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
-/// Через какое время отсутствие ответа клиента приводит к тайм-ауту
-const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Через какое время по умолчанию отсутствие ответа клиента приводит к тайм-ауту
+pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Сколько сообщений в секунду клиент может присылать; лишние отбрасываются
+pub const MESSAGES_PER_SECOND: u32 = 10;
+
+/// Сколько показ партии из архива ждёт между ходами на обычной скорости
+pub const REPLAY_DELAY: Duration = Duration::from_secs(1);
+
+/// Наибольший размер сообщения, которое клиент присылает по частям
+pub const MAX_FRAGMENTED_SIZE: usize = 64 * 1024;
+
+/// Сколько сообщений сервера может ждать отправки клиенту, прежде чем начнут
+/// выбрасываться сообщения чата
+pub const OUTBOX_CAPACITY: usize = 256;
+
+/// Сколько байт очереди сессия передаёт вебсокету за раз. Следующую порцию сессия
+/// передаёт, когда HTTP-сервер заберёт эту, а забирает он, только пока в его буфере
+/// записи есть место, то есть пока клиент успевает читать.
+pub const WRITE_CHUNK: usize = 16 * 1024;
+
+/// Сообщение, которое клиент присылает по частям во фреймах продолжения
+#[derive(Debug, Default)]
+pub struct Fragments {
+    /// Уже пришедшие части; `None`, пока сообщение не начато
+    data: Option<Vec<u8>>,
+    /// Сообщение двоичное
+    binary: bool,
+    /// Сообщение оказалось слишком большим, и его части больше не сохраняются
+    oversized: bool,
+}
+
+impl Fragments {
+    /// Добавить очередную часть. Когда приходит последняя, возвращается собранный текст
+    /// или причина, по которой его нельзя принять.
+    fn push(&mut self, item: Item) -> Option<Result<String, ProtocolError>> {
+        let (bytes, last) = match item {
+            Item::FirstText(bytes) => {
+                self.start(false);
+                (bytes, false)
+            }
+            Item::FirstBinary(bytes) => {
+                self.start(true);
+                (bytes, false)
+            }
+            Item::Continue(bytes) => (bytes, false),
+            Item::Last(bytes) => (bytes, true),
+        };
+
+        // продолжение без начала кодек вебсокета не пропускает
+        let data = self.data.as_mut()?;
+        if self.oversized || data.len() + bytes.len() > MAX_FRAGMENTED_SIZE {
+            self.oversized = true;
+            data.clear();
+        } else {
+            data.extend_from_slice(&bytes);
+        }
+        if !last {
+            return None;
+        }
+
+        let data = self.data.take()?;
+        Some(if self.oversized {
+            Err(ProtocolError::MessageTooLarge)
+        } else if self.binary {
+            Err(ProtocolError::BinaryFrame)
+        } else {
+            String::from_utf8(data).map_err(|_| ProtocolError::InvalidUtf8)
+        })
+    }
+
+    fn start(&mut self, binary: bool) {
+        self.data = Some(Vec::new());
+        self.binary = binary;
+        self.oversized = false;
+    }
+}
+
+/// Сообщения сервера, которые ещё не ушли клиенту. Если клиент не успевает их забирать,
+/// очередь не растёт без предела: выбрасываются самые старые сообщения чата, а игровые
+/// события остаются все.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    /// Сообщение в JSON и можно ли его выбросить
+    queue: VecDeque<(String, bool)>,
+    /// Сколько сообщений чата выброшено с прошлой отправки
+    dropped: usize,
+}
+
+impl Outbox {
+    /// Поставить сообщение в очередь. Возвращает `true`, если очередь была пуста и её
+    /// отправку нужно запланировать.
+    fn push(&mut self, text: String, chat: bool) -> bool {
+        let idle = self.queue.is_empty();
+        self.queue.push_back((text, chat));
+        if self.queue.len() > OUTBOX_CAPACITY {
+            if let Some(oldest) = self.queue.iter().position(|&(_, chat)| chat) {
+                self.queue.remove(oldest);
+                self.dropped += 1;
+            }
+        }
+        idle
+    }
+
+    /// Учесть сообщения чата, которые выбросил сервер, не донеся до сессии
+    fn count_dropped(&mut self, dropped: usize) {
+        self.dropped += dropped;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Забрать из начала очереди сообщения общим размером около `budget` байт, но хотя бы
+    /// одно, и число выброшенных сообщений
+    fn take_chunk(&mut self, budget: usize) -> (Vec<String>, usize) {
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        while size < budget {
+            match self.queue.pop_front() {
+                Some((text, _)) => {
+                    size += text.len();
+                    chunk.push(text);
+                }
+                None => break,
+            }
+        }
+        (chunk, std::mem::take(&mut self.dropped))
+    }
+}
+
+/// Передаёт очередь сессии вебсокету порциями по `WRITE_CHUNK`, пока очередь
+/// не опустеет. Вебсокет опрашивает сессию, только когда HTTP-сервер готов взять
+/// очередную порцию, поэтому у медленного клиента сообщения ждут в ограниченной очереди
+/// сессии, а не в буфере записи.
+struct Drain;
+
+impl ActorFuture<WsChatSession> for Drain {
+    type Output = ();
+
+    fn poll(
+        self: Pin<&mut Self>,
+        act: &mut WsChatSession,
+        ctx: &mut ws::WebsocketContext<WsChatSession>,
+        task: &mut task::Context<'_>,
+    ) -> Poll<()> {
+        act.write_chunk(ctx);
+        if act.outbox.is_empty() {
+            Poll::Ready(())
+        } else {
+            task.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Задержка до клиента, измеренная по ответам на пинги сердцебиения
+#[derive(Debug, Default)]
+pub struct Latency {
+    /// Номер последнего отправленного пинга; ответы на более ранние пинги не учитываются
+    seq: u64,
+    /// Когда был отправлен последний пинг, пока на него не пришёл ответ
+    sent: Option<Instant>,
+    /// Скользящее среднее времени ответа
+    average: Option<Duration>,
+}
+
+impl Latency {
+    /// Запомнить отправку нового пинга и вернуть его содержимое
+    fn ping(&mut self, now: Instant) -> [u8; 8] {
+        self.seq += 1;
+        self.sent = Some(now);
+        self.seq.to_be_bytes()
+    }
+
+    /// Учесть ответ на пинг. Возвращает новое среднее, если ответ пришёл
+    /// на последний отправленный пинг.
+    fn pong(&mut self, payload: &[u8], now: Instant) -> Option<Duration> {
+        if payload != self.seq.to_be_bytes() {
+            return None;
+        }
+        let rtt = now.saturating_duration_since(self.sent.take()?);
+        // новый замер весит четверть, чтобы единичный всплеск не искажал среднее
+        let average = match self.average {
+            Some(average) => (average * 3 + rtt) / 4,
+            None => rtt,
+        };
+        self.average = Some(average);
+        Some(average)
+    }
+}
 
 #[derive(Debug)]
 pub struct WsChatSession {
     /// уникальный идентификатор сессии
     pub id: usize,
 
-    /// Клиент должен отправлять ping не реже одного раза в 10 секунд (CLIENT_TIMEOUT), иначе мы разрываем соединение.
+    /// Клиент должен отправлять ping не реже одного раза за `client_timeout`, иначе мы разрываем соединение.
     pub hb: Instant,
 
-    /// объединённая комната
+    /// как часто клиенту отправляются пинги
+    pub heartbeat_interval: Duration,
+
+    /// сколько можно ждать ответа клиента, прежде чем разорвать соединение
+    pub client_timeout: Duration,
+
+    /// текущая комната: в неё уходят сообщения чата и команды без указания комнаты
     pub room: String,
 
+    /// все комнаты, в которых находится сессия
+    pub rooms: HashSet<String>,
+
+    /// комната, куда сервер помещает новые сессии; её нельзя покинуть
+    pub lobby: String,
+
     /// имя коллеги
     pub name: Option<String>,
 
+    /// какой стороной к себе клиент показывает доску
+    pub orientation: Option<Color>,
+
+    /// токен прежней сессии, которую клиент хочет продолжить
+    pub reconnect: Option<Uuid>,
+
+    /// комнаты, в которые сессия вошла командой `/spectate` и где не может ходить
+    pub spectating: HashSet<String>,
+
+    /// ограничитель частоты сообщений клиента
+    pub limiter: TokenBucket,
+
+    /// задержка до клиента
+    pub latency: Latency,
+
+    /// сообщение, которое клиент присылает по частям
+    pub fragments: Fragments,
+
+    /// таймер показа партии из архива, пока он идёт
+    pub replay: Option<SpawnHandle>,
+
+    /// сообщения сервера, которые ещё не отправлены клиенту
+    pub outbox: Outbox,
+
+    /// режим движка: ходы приходят и уходят строками UCI без JSON
+    pub engine_mode: bool,
+
+    /// клиент сам закрыл соединение обычным кодом закрытия, а не пропал
+    pub clean_close: bool,
+
     /// Сервер чата
     pub addr: Addr<server::ChatServer>,
 }
@@ -34,14 +281,33 @@ impl WsChatSession {
     ///
     /// также этот метод проверяет сердцебиение клиента
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            // сервер чата остановился: сессии больше не с кем работать
+            if !act.addr.connected() {
+                log::info!(
+                    "session {} in room {}: chat server stopped",
+                    act.id,
+                    act.room
+                );
+                ctx.close(Some(ws::CloseCode::Away.into()));
+                ctx.stop();
+                return;
+            }
+
             // проверять сердцебиение клиента
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.client_timeout {
                 // сердцебиение прервано
-                println!("Websocket Client heartbeat failed, disconnecting!");
+                log::warn!(
+                    "session {} in room {}: heartbeat failed, disconnecting",
+                    act.id,
+                    act.room
+                );
 
                 // уведомлять сервер чата
-                act.addr.do_send(server::Disconnect { id: act.id });
+                act.addr.do_send(server::Disconnect {
+                    id: act.id,
+                    clean: false,
+                });
 
                 // остановить актёра
                 ctx.stop();
@@ -50,8 +316,849 @@ impl WsChatSession {
                 return;
             }
 
-            ctx.ping(b"");
+            let payload = act.latency.ping(Instant::now());
+            ctx.ping(&payload);
+        });
+    }
+
+    /// отправить событие своему клиенту; сообщения сервера, пришедшие раньше, уходят
+    /// перед ним
+    fn send_event(&mut self, ctx: &mut ws::WebsocketContext<Self>, event: &ServerEvent) {
+        self.enqueue(ctx, event.to_json(), false);
+    }
+
+    /// поставить сообщение в очередь к клиенту и, если очередь стояла, запустить её передачу
+    fn enqueue(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: String, chat: bool) {
+        if self.outbox.push(text, chat) {
+            ctx.spawn(Drain);
+        }
+    }
+
+    /// передать вебсокету очередную порцию сообщений и, если часть чата пришлось
+    /// выбросить, сказать сколько
+    fn write_chunk(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let (chunk, dropped) = self.outbox.take_chunk(WRITE_CHUNK);
+        if dropped > 0 {
+            log::warn!(
+                "session {} in room {}: client is lagging, dropped {} chat messages",
+                self.id,
+                self.room,
+                dropped
+            );
+            ctx.text(ServerEvent::Lag { dropped }.to_json());
+        }
+        for text in chunk {
+            ctx.text(text);
+        }
+    }
+
+    /// войти в комнату и сделать её текущей, если сервер её принял; прежние комнаты
+    /// сессия не покидает
+    fn join(&mut self, join: server::Join, ctx: &mut ws::WebsocketContext<Self>) {
+        let room = join.name.clone();
+        let spectate = join.spectate;
+        self.addr
+            .send(join)
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => {
+                        if spectate {
+                            act.spectating.insert(room.clone());
+                        } else {
+                            act.spectating.remove(&room);
+                        }
+                        act.rooms.insert(room.clone());
+                        act.room = room;
+                        act.send_event(ctx, &ServerEvent::system("joined"));
+                    }
+                    Ok(Err(err)) => act.send_event(ctx, &err),
+                    _ => log::warn!(
+                        "session {} in room {}: chat server did not reply",
+                        act.id,
+                        act.room
+                    ),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+
+    /// показывать клиенту партию из архива по ходу за раз; прежний показ прекращается
+    fn start_replay(
+        &mut self,
+        id: GameId,
+        events: Vec<ServerEvent>,
+        speed: u32,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        self.stop_replay(ctx);
+        let mut events = events.into_iter();
+        if let Some(start) = events.next() {
+            self.send_event(ctx, &start);
+        }
+        let handle = ctx.run_interval(REPLAY_DELAY / speed, move |act, ctx| match events.next() {
+            Some(event) => act.send_event(ctx, &event),
+            None => {
+                act.send_event(ctx, &ServerEvent::ReplayEnd { id });
+                act.stop_replay(ctx);
+            }
         });
+        self.replay = Some(handle);
+    }
+
+    /// прекратить показ партии из архива; `false`, если он не шёл
+    fn stop_replay(&mut self, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        match self.replay.take() {
+            Some(handle) => ctx.cancel_future(handle),
+            None => false,
+        }
+    }
+
+    /// выполнить текстовое сообщение клиента
+    fn handle_text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        if !self.limiter.try_take(Instant::now()) {
+            self.send_event(ctx, &ServerEvent::error("rate_limited", "rate limited"));
+            return;
+        }
+        // JSON-объекты разбираются по протоколу, остальной текст — как текстовые команды
+        if text.trim_start().starts_with('{') {
+            match protocol::parse_client_json(text) {
+                Ok(command) => self.handle_command(command, ctx),
+                Err(err) => self.send_protocol_error(ctx, err),
+            }
+        } else if let Some(mv) = Move::parse(text.trim()).filter(|_| self.engine_mode) {
+            // движок присылает свой ход строкой UCI в текущую комнату
+            self.handle_command(
+                ClientCommand::ChessStep {
+                    room: None,
+                    ply: None,
+                    step: mv.to_string(),
+                },
+                ctx,
+            );
+        } else {
+            match ClientCommand::parse(text) {
+                Ok(command) => self.handle_command(command, ctx),
+                Err(err) => self.send_event(ctx, &ServerEvent::error(err.code(), err.to_string())),
+            }
+        }
+    }
+
+    /// сообщить клиенту, что его сообщение не удалось разобрать
+    fn send_protocol_error(&mut self, ctx: &mut ws::WebsocketContext<Self>, err: ProtocolError) {
+        log::warn!(
+            "session {} in room {}: protocol error: {}",
+            self.id,
+            self.room,
+            err
+        );
+        self.send_event(ctx, &ServerEvent::error("protocol_error", err.to_string()));
+    }
+
+    /// отправить сообщение чата в текущую комнату
+    fn chat(&self, scope: ChatScope, text: String) {
+        let msg = if let Some(ref name) = self.name {
+            format!("{}: {}", name, text)
+        } else {
+            text
+        };
+        // отправить сообщение на сервер чата
+        self.addr.do_send(server::ClientMessage {
+            id: self.id,
+            msg,
+            room: self.room.clone(),
+            scope,
+        })
+    }
+
+    /// выполнить разобранную команду клиента
+    fn handle_command(&mut self, command: ClientCommand, ctx: &mut ws::WebsocketContext<Self>) {
+        match command {
+            // зритель, вошедший через `/spectate`, не может занять место за доской
+            ClientCommand::Promote if self.spectating.contains(&self.room) => self.send_event(
+                ctx,
+                &ServerEvent::error(
+                    "read_only",
+                    "you joined with /spectate; rejoin to take a seat",
+                ),
+            ),
+            ClientCommand::ChessStep { room, ply, step } => {
+                let room = room.unwrap_or_else(|| self.room.clone());
+                log::debug!("session {} in room {}: /chess_step {}", self.id, room, step);
+
+                if !self.rooms.contains(&room) {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::error("not_in_room", format!("you are not in room {}", room)),
+                    );
+                } else if self.spectating.contains(&room) {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::error("spectator", "spectators cannot move"),
+                    );
+                } else {
+                    self.addr.do_send(server::ChessGame {
+                        id: self.id,
+                        step,
+                        room,
+                        ply,
+                        premove: false,
+                    });
+                }
+            }
+            ClientCommand::Premove(step) => {
+                if self.spectating.contains(&self.room) {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::error("spectator", "spectators cannot move"),
+                    );
+                } else {
+                    self.addr.do_send(server::Premove {
+                        id: self.id,
+                        step,
+                        room: self.room.clone(),
+                    });
+                }
+            }
+            ClientCommand::Leave(room) => {
+                let room = room.unwrap_or_else(|| self.room.clone());
+                if room == self.lobby {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::error("cannot_leave_lobby", "cannot leave the lobby"),
+                    );
+                } else if self.rooms.remove(&room) {
+                    self.spectating.remove(&room);
+                    self.addr.do_send(server::Leave {
+                        id: self.id,
+                        room: room.clone(),
+                    });
+                    if self.room == room {
+                        self.room = self.lobby.clone();
+                    }
+                    self.send_event(ctx, &ServerEvent::system(format!("left {}", room)));
+                } else {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::error("not_in_room", format!("you are not in room {}", room)),
+                    );
+                }
+            }
+            ClientCommand::Mirror => {
+                self.addr.do_send(server::Mirror {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::Rematch => {
+                self.addr.do_send(server::Rematch {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::Resign => {
+                self.addr.do_send(server::Resign {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::Abort => {
+                self.addr.do_send(server::Abort {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::ClaimDraw => {
+                self.addr.do_send(server::ClaimDraw {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::OfferDraw => {
+                self.addr.do_send(server::OfferDraw {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::AcceptDraw => {
+                self.addr.do_send(server::AcceptDraw {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::DeclineDraw => {
+                self.addr.do_send(server::DeclineDraw {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::ProposeTime(time_control) => {
+                self.addr.do_send(server::ProposeTime {
+                    id: self.id,
+                    room: self.room.clone(),
+                    time_control,
+                });
+            }
+            ClientCommand::AcceptTime => {
+                self.addr.do_send(server::AcceptTime {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::OfferTakeback => {
+                self.addr.do_send(server::OfferTakeback {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::Berserk => {
+                self.addr.do_send(server::Berserk {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::AcceptTakeback => {
+                self.addr.do_send(server::AcceptTakeback {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::QuickMatch => {
+                self.addr.do_send(server::QuickMatch {
+                    id: self.id,
+                    addr: ctx.address().recipient(),
+                });
+            }
+            ClientCommand::PlayBot => {
+                self.addr.do_send(server::PlayBot {
+                    id: self.id,
+                    addr: ctx.address().recipient(),
+                });
+            }
+            ClientCommand::Promote => {
+                self.addr.do_send(server::Promote {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::Flag => {
+                self.addr.do_send(server::Flag {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::WantSeat => {
+                self.addr.do_send(server::WantSeat {
+                    id: self.id,
+                    room: self.room.clone(),
+                });
+            }
+            ClientCommand::PassSeat(to) => {
+                self.addr.do_send(server::PassSeat {
+                    id: self.id,
+                    room: self.room.clone(),
+                    to,
+                });
+            }
+            ClientCommand::ListRooms => {
+                // Отправьте сообщение ListRooms на сервер чата и дождитесь ответа
+                log::debug!("session {} in room {}: /list", self.id, self.room);
+                self.addr
+                    .send(server::ListRooms)
+                    .into_actor(self)
+                    .then(|res, act, ctx| {
+                        match res {
+                            Ok(rooms) => act.send_event(ctx, &ServerEvent::Rooms { rooms }),
+                            _ => log::warn!(
+                                "session {} in room {}: chat server did not reply",
+                                act.id,
+                                act.room
+                            ),
+                        }
+                        fut::ready(())
+                    })
+                    .wait(ctx)
+                // .wait(ctx) приостанавливает все события в контексте,
+                // поэтому актер не будет получать новые сообщения, пока не получит список
+                // комнат назад
+            }
+            ClientCommand::WhoAmI => self
+                .addr
+                .send(server::WhoAmI {
+                    id: self.id,
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(whoami) => act.send_event(ctx, &whoami),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Turn => self
+                .addr
+                .send(server::GetTurn {
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Some(color)) => act.send_event(ctx, &ServerEvent::Turn { color }),
+                        Ok(None) => act.send_event(
+                            ctx,
+                            &ServerEvent::error("no_game", "no game in this room"),
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::DrawStatus => self
+                .addr
+                .send(server::GetDrawOffer {
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(by) => act.send_event(
+                            ctx,
+                            &ServerEvent::DrawStatus {
+                                pending: by.is_some(),
+                                by,
+                            },
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Status => self
+                .addr
+                .send(server::GetStatus {
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Some(status)) => act.send_event(ctx, &status),
+                        Ok(None) => act.send_event(
+                            ctx,
+                            &ServerEvent::error("no_game", "no game in this room"),
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::CheckMove(step) => self
+                .addr
+                .send(server::CheckMove {
+                    room: self.room.clone(),
+                    step,
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(check) => act.send_event(ctx, &check),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Perft(depth) => self
+                .addr
+                .send(server::Perft {
+                    room: self.room.clone(),
+                    depth,
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(perft) => act.send_event(ctx, &perft),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Hint => self
+                .addr
+                .send(server::Hint {
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(hint) => act.send_event(ctx, &hint),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::ListGames => self
+                .addr
+                .send(server::ListGames)
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(games) => act.send_event(ctx, &ServerEvent::Games { games }),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Stats => self
+                .addr
+                .send(server::ServerStats)
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(stats) => act.send_event(ctx, &stats),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Players(room) => self
+                .addr
+                .send(server::PlayerList {
+                    room: room.unwrap_or_else(|| self.room.clone()),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Some(players)) => act.send_event(ctx, &players),
+                        Ok(None) => act.send_event(
+                            ctx,
+                            &ServerEvent::error("no_game", "no game in this room"),
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Rating(name) => self
+                .addr
+                .send(server::GetRating { name: name.clone() })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(rating) => act.send_event(ctx, &ServerEvent::Rating { name, rating }),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Archive(id) => self
+                .addr
+                .send(server::GetArchived { id })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Some(game)) => act.send_event(ctx, &game),
+                        Ok(None) => {
+                            act.send_event(ctx, &ServerEvent::error("no_such_game", "no such game"))
+                        }
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Replay { id, speed } => self
+                .addr
+                .send(server::GetReplay { id })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(Some(events)) => act.start_replay(id, events, speed, ctx),
+                        Ok(None) => {
+                            act.send_event(ctx, &ServerEvent::error("no_such_game", "no such game"))
+                        }
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::ReplayStop => {
+                if self.stop_replay(ctx) {
+                    self.send_event(ctx, &ServerEvent::system("replay stopped"));
+                } else {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::error("no_replay", "no replay in progress"),
+                    );
+                }
+            }
+            ClientCommand::Moves(from) => self
+                .addr
+                .send(server::GetMoves {
+                    room: self.room.clone(),
+                    from,
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(Some(targets)) => act.send_event(
+                            ctx,
+                            &ServerEvent::Moves {
+                                from: from.to_string(),
+                                to: targets.iter().map(Square::to_string).collect(),
+                            },
+                        ),
+                        Ok(None) => act.send_event(
+                            ctx,
+                            &ServerEvent::error("no_game", "no game in this room"),
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::History => self
+                .addr
+                .send(server::GetHistory {
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Some(moves)) => act.send_event(ctx, &ServerEvent::History { moves }),
+                        Ok(None) => act.send_event(
+                            ctx,
+                            &ServerEvent::error("no_game", "no game in this room"),
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Pgn => self
+                .addr
+                .send(server::GetPgn {
+                    room: self.room.clone(),
+                })
+                .into_actor(self)
+                .then(|res, act, ctx| {
+                    match res {
+                        Ok(Some(data)) => act.send_event(ctx, &ServerEvent::Pgn { data }),
+                        Ok(None) => act.send_event(
+                            ctx,
+                            &ServerEvent::error("no_game", "no game in this room"),
+                        ),
+                        _ => log::warn!(
+                            "session {} in room {}: chat server did not reply",
+                            act.id,
+                            act.room
+                        ),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx),
+            ClientCommand::Join { room, password } => self.join(
+                server::Join {
+                    id: self.id,
+                    name: room,
+                    password,
+                    ..Default::default()
+                },
+                ctx,
+            ),
+            ClientCommand::Create { room, password } => self.join(
+                server::Join {
+                    id: self.id,
+                    name: room,
+                    password: Some(password),
+                    create: true,
+                    ..Default::default()
+                },
+                ctx,
+            ),
+            ClientCommand::CreateGame { room, config } => self.join(
+                server::Join {
+                    id: self.id,
+                    name: room,
+                    create: true,
+                    config: Some(config),
+                    ..Default::default()
+                },
+                ctx,
+            ),
+            ClientCommand::Spectate { room, password } => self.join(
+                server::Join {
+                    id: self.id,
+                    name: room,
+                    password,
+                    spectate: true,
+                    ..Default::default()
+                },
+                ctx,
+            ),
+            ClientCommand::JoinFen { room, fen } => match Board::from_fen(&fen) {
+                Ok(board) => self.join(
+                    server::Join {
+                        id: self.id,
+                        name: room,
+                        board: Some(board),
+                        create: true,
+                        // позицию можно выбрать заранее выигранной, поэтому партия
+                        // из неё идёт без часов и рейтинга
+                        config: Some(GameConfig {
+                            time_control: None,
+                            rated: false,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ctx,
+                ),
+                Err(err) => self.send_event(
+                    ctx,
+                    &ServerEvent::error("invalid_fen", format!("invalid fen: {}", err)),
+                ),
+            },
+            ClientCommand::LoadPgn { room, pgn } => match pgn::from_pgn(&pgn) {
+                Ok((board, moves)) => self.join(
+                    server::Join {
+                        id: self.id,
+                        name: room,
+                        board: Some(board),
+                        moves,
+                        create: true,
+                        // загруженная партия для разбора: без часов и рейтинга, а её ходы
+                        // сделаны мгновенно и ничего не говорят о скорости игроков
+                        config: Some(GameConfig {
+                            time_control: None,
+                            rated: false,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ctx,
+                ),
+                Err(err) => self.send_event(
+                    ctx,
+                    &ServerEvent::error("invalid_pgn", format!("invalid pgn: {}", err)),
+                ),
+            },
+            ClientCommand::Solo(room) => self.join(
+                server::Join {
+                    id: self.id,
+                    name: room,
+                    create: true,
+                    // партия для разбора: без часов и рейтинга
+                    config: Some(GameConfig {
+                        time_control: None,
+                        rated: false,
+                        ..Default::default()
+                    }),
+                    solo: true,
+                    ..Default::default()
+                },
+                ctx,
+            ),
+            ClientCommand::Join960(room) => self.join(
+                server::Join {
+                    id: self.id,
+                    name: room,
+                    create: true,
+                    config: Some(GameConfig {
+                        variant: Variant::Chess960,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ctx,
+            ),
+            ClientCommand::Name(name) => {
+                self.addr.do_send(server::SetName {
+                    id: self.id,
+                    name: name.clone(),
+                });
+                self.name = Some(name);
+            }
+            ClientCommand::EngineMode => {
+                self.engine_mode = !self.engine_mode;
+                let state = if self.engine_mode { "on" } else { "off" };
+                self.send_event(ctx, &ServerEvent::system(format!("engine mode {}", state)));
+            }
+            ClientCommand::Orientation(orientation) => {
+                self.addr.do_send(server::SetOrientation {
+                    id: self.id,
+                    orientation,
+                });
+                self.orientation = Some(orientation);
+                self.send_event(ctx, &ServerEvent::Orientation { orientation });
+            }
+            ClientCommand::MuteSpectators(muted) => {
+                self.addr
+                    .do_send(server::MuteSpectators { id: self.id, muted });
+            }
+            ClientCommand::Say { scope, text } => self.chat(scope, text),
+            ClientCommand::Chat(text) => self.chat(ChatScope::All, text),
+        }
     }
 }
 
@@ -72,12 +1179,25 @@ impl Actor for WsChatSession {
         let addr = ctx.address();
         self.addr
             .send(server::Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                seated: addr.recipient(),
+                reconnect: self.reconnect,
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = res,
+                    Ok(connected) => {
+                        act.id = connected.id;
+                        act.room = connected.room;
+                        act.rooms = connected.rooms;
+                        act.lobby = connected.lobby;
+                        act.name = connected.name;
+                        // вернувшийся клиент восстанавливает вид доски
+                        if let Some(orientation) = connected.orientation {
+                            act.orientation = Some(orientation);
+                            act.send_event(ctx, &ServerEvent::Orientation { orientation });
+                        }
+                    }
                     // что-то не так с сервером чата
                     _ => ctx.stop(),
                 }
@@ -88,7 +1208,10 @@ impl Actor for WsChatSession {
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         // уведомлять сервер чата
-        self.addr.do_send(server::Disconnect { id: self.id });
+        self.addr.do_send(server::Disconnect {
+            id: self.id,
+            clean: self.clean_close,
+        });
         Running::Stop
     }
 }
@@ -98,7 +1221,40 @@ impl Handler<server::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        self.outbox.count_dropped(msg.dropped);
+        // движку ход соперника уходит строкой UCI вместо события в JSON
+        let text = match msg.uci {
+            Some(uci) if self.engine_mode => uci,
+            _ => msg.text,
+        };
+        self.enqueue(ctx, text, msg.chat);
+    }
+}
+
+/// Сервер нашёл соперника и уже перевёл сессию в комнату партии
+impl Handler<server::Matched> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Matched, ctx: &mut Self::Context) {
+        self.send_event(
+            ctx,
+            &ServerEvent::Matched {
+                room: msg.room.clone(),
+                color: msg.color,
+            },
+        );
+        self.spectating.remove(&msg.room);
+        self.rooms.insert(msg.room.clone());
+        self.room = msg.room;
+    }
+}
+
+/// Игрок уступил сессии место: в этой комнате она больше не только смотрит
+impl Handler<server::Seated> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Seated, _: &mut Self::Context) {
+        self.spectating.remove(&msg.room);
     }
 }
 
@@ -113,116 +1269,53 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             Ok(msg) => msg,
         };
 
-        //log::debug!("WEBSOCKET MESSAGE: {:?}", msg);
+        log::debug!(
+            "session {} in room {}: websocket message {:?}",
+            self.id,
+            self.room,
+            msg
+        );
         match msg {
             ws::Message::Ping(msg) => {
                 self.hb = Instant::now();
                 ctx.pong(&msg);
             }
-            ws::Message::Pong(_) => {
+            ws::Message::Pong(payload) => {
                 self.hb = Instant::now();
-            }
-            ws::Message::Text(text) => {
-                let m = text.trim();
-                // мы проверяем сообщения типа /sss
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/chess-step" => {
-                            if v.len() == 2 {
-
-                                println!("{}, {}", v[0], v[1]);
-
-                                self.addr.do_send(server::ChessGame {
-                                    id: self.id,
-                                    step: v[0].to_string() + v[1],
-                                    room: self.room.clone(),
-                                });
-                            } else {
-                                ctx.text("step is wrong");
-                            }
-                        }
-                        "/restart_game" => {
-                            self.addr.do_send(server::ChessGame {
-                                id: self.id,
-                                step: "restart_game".to_string(),
-                                room: self.room.clone(),
-                            });
-                        }
-                        "/give_up" => {
-                            self.addr.do_send(server::ChessGame {
-                                id: self.id,
-                                step: "give_up".to_string(),
-                                room: self.room.clone(),
-                            });
-                        }
-                        "/list" => {
-                            // Отправьте сообщение ListRooms на сервер чата и дождитесь ответа
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) приостанавливает все события в контексте,
-                            // поэтому актер не будет получать новые сообщения, пока не получит список
-                            // комнат назад
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {:?}", m)),
-                    }
-                } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{}: {}", name, m)
-                    } else {
-                        m.to_owned()
-                    };
-                    // отправить сообщение на сервер чата
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
+                if let Some(average) = self.latency.pong(&payload, self.hb) {
+                    self.send_event(
+                        ctx,
+                        &ServerEvent::Ping {
+                            rtt_ms: average.as_millis(),
+                        },
+                    );
                 }
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
+            ws::Message::Text(text) => self.handle_text(&text, ctx),
+            ws::Message::Binary(_) => self.send_protocol_error(ctx, ProtocolError::BinaryFrame),
             ws::Message::Close(reason) => {
+                // закрытие без кода тоже считается обычным
+                let code = reason.as_ref().map(|reason| reason.code);
+                self.clean_close = matches!(
+                    code,
+                    None | Some(ws::CloseCode::Normal | ws::CloseCode::Away)
+                );
+                log::info!(
+                    "session {} in room {}: client closed the connection with code {:?}",
+                    self.id,
+                    self.room,
+                    code
+                );
                 ctx.close(reason);
                 ctx.stop();
             }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
-            }
+            // большое сообщение может прийти по частям: оно выполняется, когда собрано целиком
+            ws::Message::Continuation(item) => match self.fragments.push(item) {
+                Some(Ok(text)) => self.handle_text(&text, ctx),
+                Some(Err(err)) => self.send_protocol_error(ctx, err),
+                None => {}
+            },
             ws::Message::Nop => (),
         }
     }
-}
\ No newline at end of file
+}